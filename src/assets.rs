@@ -1,15 +1,19 @@
 use ggez::{graphics, Context, GameResult};
 
+use std::collections::HashMap;
+
 pub struct Assets {
     pub player_ship: graphics::Image,
     pub enemy_ship: graphics::Image,
-    pub player_bullet: graphics::Image,
     pub other_players: graphics::Image,
     pub enemy_bullet: graphics::Image,
     pub player_dead: graphics::Image,
-    pub special_bullet: graphics::Image,
     pub shield: graphics::Image,
     pub font: graphics::Font,
+    // the image enemy_ship was loaded from
+    boss_sprite: String,
+    // bullets and regular enemies, by path
+    sprites: HashMap<String, Option<graphics::Image>>,
 }
 
 impl Assets {
@@ -19,13 +23,37 @@ impl Assets {
                 player_ship: graphics::Image::new(ctx, "/player_shipv1.png")?,
                 enemy_ship: graphics::Image::new(ctx, "/enemy_ship.png")?,
                 other_players: graphics::Image::new(ctx, "/player_shipv2.png")?,
-                player_bullet: graphics::Image::new(ctx, "/player_bullet2.png")?,
                 enemy_bullet: graphics::Image::new(ctx, "/enemy_bullet.png")?,
                 player_dead: graphics::Image::new(ctx, "/player_ship_dead.png")?,
-                special_bullet: graphics::Image::new(ctx, "/special_bullet.png")?,
                 font: graphics::Font::new(ctx, "/ARCADE_N.TTF")?,
                 shield: graphics::Image::new(ctx, "/shieldv2.png")?,
+                boss_sprite: String::from("/enemy_ship.png"),
+                sprites: HashMap::new(),
             }
           )
     }
+
+    // loaded the first time it's drawn, None when it can't be
+    pub fn sprite(&mut self, ctx: &mut Context, path: &str) -> Option<&graphics::Image> {
+        if !self.sprites.contains_key(path) {
+            let image = graphics::Image::new(ctx, path)
+                .map_err(|e| eprintln!("couldn't load the sprite {}: {}", path, e))
+                .ok();
+            self.sprites.insert(path.to_string(), image);
+        }
+        self.sprites[path].as_ref()
+    }
+
+    // swaps the boss image for the one of the boss being played
+    pub fn use_boss_sprite(&mut self, ctx: &mut Context, sprite: &str) {
+        if self.boss_sprite == sprite {
+            return
+        }
+        match graphics::Image::new(ctx, sprite) {
+            Ok(image) => self.enemy_ship = image,
+            Err(e) => eprintln!("couldn't load the boss sprite {}: {}", sprite, e),
+        }
+        // not tried again every frame when it failed
+        self.boss_sprite = sprite.to_string();
+    }
 }
@@ -0,0 +1,69 @@
+use orchid::cli::{ self, CliError, Mode };
+use orchid::network::Endpoint;
+use orchid::server::Server;
+use orchid::transport::{ self, TransportKind };
+use orchid::boss;
+use orchid::level::{ self, LevelDefinition };
+
+use std::env;
+use std::error::Error;
+use std::process;
+
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256Plus;
+use rand::Rng;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = match cli::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(CliError::Help) => {
+            println!("{}", cli::USAGE);
+            process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        },
+    };
+
+    if let Mode::Join(_) | Mode::Browse = config.mode {
+        eprintln!("error: the server can only host matches\n\n{}", cli::USAGE);
+        process::exit(2);
+    }
+    if config.lockstep {
+        eprintln!("error: lockstep matches are peer to peer, they don't need a server\n\n{}", cli::USAGE);
+        process::exit(2);
+    }
+
+    let resource_dir = orchid::resource_dir();
+    let loaded = match &config.boss {
+        Some(name) => boss::load(&resource_dir, name).map(|boss| (LevelDefinition::default(), boss)).map_err(Into::into),
+        None => level::load(&resource_dir, &config.level),
+    };
+    let (level, boss) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        },
+    };
+
+    let transport = transport::bind(config.transport, config.bind_address())?;
+
+    println!("orchid-server '{}' listening on {}", config.name, transport.local_id()?);
+
+    let seed = config.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>());
+    let rng = Xoshiro256Plus::seed_from_u64(seed);
+    let mut endpoint = Endpoint::new(transport);
+    endpoint.timeout = config.timeout;
+    endpoint.netsim.conditions = config.conditions;
+    let mut server = Server::new(endpoint, rng, config.name.clone());
+    server.stats_interval = config.stats_interval;
+    server.set_boss(boss);
+    server.set_level(level);
+    if config.transport != TransportKind::Udp {
+        server.disable_discovery();
+    }
+
+    server.run()
+}
@@ -0,0 +1,336 @@
+use crate::constants::*;
+use crate::bullets::{ BulletKind, BulletType, BulletTypes };
+use crate::entities::{ Ship, Bullet };
+use crate::pattern::Timeline;
+use crate::Point;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256Plus;
+
+use serde::{Serialize, Deserialize};
+
+//=================
+//   DEFINITION
+//=================
+
+// A boss as written in `resources/bosses/<name>.ron`. Only the host or the
+// server reads the file, it sends the definition to everyone else.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BossDefinition {
+    pub name: String,
+    // image in the resources, i.e. "/enemy_ship.png"
+    pub sprite: String,
+    // bullets closer than this plus their own hitbox to the center hit
+    pub hitbox: f32,
+    pub health: f32,
+    pub spawn: Point,
+    // played in order, starting with the first one
+    pub phases: Vec<Phase>,
+    // kinds of bullets the attacks can fire besides the built in ones
+    #[serde(default)]
+    pub bullets: Vec<BulletKind>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Movement {
+    Still,
+    // left and right across the whole screen, in pixels per second
+    Oscillate { speed: f32 },
+    // from point to point at a constant speed, back to the first one after the last
+    Path { speed: f32, points: Vec<Point> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Phase {
+    // played when the phase starts, the boss can't be hurt meanwhile
+    #[serde(default)]
+    pub transition: Transition,
+    pub movement: Movement,
+    pub timeline: Timeline,
+    // what ends the phase, the next one starts then
+    #[serde(default)]
+    pub until: Trigger,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum Trigger {
+    #[default]
+    Never,
+    // the health is down to this share of the maximum
+    Health(f32),
+    // seconds since the phase started, the last phase goes back to the first
+    Time(f32),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Transition {
+    // seconds, the boss neither moves nor fires during them
+    pub duration: f32,
+    pub animation: Animation,
+}
+
+impl Default for Transition {
+    fn default() -> Transition {
+        Transition {
+            duration: 0.0,
+            animation: Animation::Blink,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Animation {
+    Blink,
+    Spin,
+}
+
+impl BossDefinition {
+    pub fn parse(source: &str) -> Result<BossDefinition, BossError> {
+        let definition: BossDefinition = ron::from_str(source)
+            .map_err(|e| BossError::Parse(e.to_string()))?;
+        definition.validate()?;
+        Ok(definition)
+    }
+
+    // also checked when it comes from the network
+    pub fn validate(&self) -> Result<(), BossError> {
+        if !(self.health > 0.0 && self.health.is_finite()) {
+            return Err(BossError::Invalid("health has to be positive"))
+        }
+        if !(self.hitbox > 0.0 && self.hitbox.is_finite()) {
+            return Err(BossError::Invalid("hitbox has to be positive"))
+        }
+        if self.phases.is_empty() {
+            return Err(BossError::Invalid("there has to be at least one phase"))
+        }
+        for (i, kind) in self.bullets.iter().enumerate() {
+            kind.validate().map_err(BossError::Invalid)?;
+            if BulletTypes::is_builtin(&kind.name) || self.bullets[..i].iter().any(|other| other.name == kind.name) {
+                return Err(BossError::Invalid("two kinds of bullets have the same name"))
+            }
+        }
+        let types = BulletTypes::new(&self.bullets);
+        for phase in &self.phases {
+            phase.validate()?;
+            let cues = &phase.timeline.cues;
+            if cues.iter().any(|cue| types.find(&cue.attack.bullet).is_none()) {
+                return Err(BossError::Invalid("an attack fires an unknown kind of bullet"))
+            }
+        }
+        // it would start again and again, the health doesn't go back up
+        if let Some(Trigger::Health(_)) = self.phases.last().map(|phase| phase.until) {
+            return Err(BossError::Invalid("the last phase can only end on a timer"))
+        }
+        if bincode::serialized_size(self).map_or(true, |size| size > MAX_BOSS_SIZE) {
+            return Err(BossError::Invalid("too big to be sent to the other players"))
+        }
+        Ok(())
+    }
+
+    // the phase that should follow the current one, if it's over
+    pub fn next_phase(&self, state: &BossState, health: f32) -> Option<usize> {
+        let over = match self.phases.get(state.phase)?.until {
+            Trigger::Never => false,
+            Trigger::Health(share) => health <= self.health * share,
+            Trigger::Time(seconds) => state.clock >= seconds,
+        };
+        if over { Some((state.phase + 1) % self.phases.len()) } else { None }
+    }
+}
+
+impl Phase {
+    fn validate(&self) -> Result<(), BossError> {
+        self.timeline.validate().map_err(BossError::Invalid)?;
+        if !(self.transition.duration >= 0.0 && self.transition.duration.is_finite()) {
+            return Err(BossError::Invalid("a transition can't last a negative time"))
+        }
+        self.movement.validate().map_err(BossError::Invalid)?;
+        match self.until {
+            Trigger::Health(share) if !(share > 0.0 && share <= 1.0) => {
+                Err(BossError::Invalid("a health trigger is a share between 0 and 1"))
+            },
+            Trigger::Time(seconds) if !(seconds > 0.0 && seconds.is_finite()) => {
+                Err(BossError::Invalid("a time trigger has to be positive"))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// the boss every match had before they could be picked
+impl Default for BossDefinition {
+    fn default() -> BossDefinition {
+        BossDefinition::parse(include_str!("../resources/bosses/orchid.ron")).unwrap()
+    }
+}
+
+// Reads `bosses/<name>.ron` from the resources.
+pub fn load(resource_dir: &Path, name: &str) -> Result<BossDefinition, BossError> {
+    let path = resource_dir.join("bosses").join(format!("{}.ron", name));
+    let source = fs::read_to_string(&path)
+        .map_err(|e| BossError::Io(path.display().to_string(), e))?;
+    BossDefinition::parse(&source)
+}
+
+//=================
+//   BOSS STATE
+//=================
+
+// Where the boss is in its phases. The authority decides when a phase
+// ends, or every peer in lockstep, the others are told with a PhaseSignal.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct BossState {
+    pub phase: usize,
+    // seconds since the phase started
+    pub clock: f32,
+    // seconds left of the transition
+    pub transition: f32,
+    // the point of a path the boss is heading to
+    pub waypoint: usize,
+    // seconds into the timeline of attacks, it starts after the transition
+    pub timeline: f32,
+    // how far into the timeline the attacks were fired
+    pub fired: f32,
+    // drawn from the shared rng when the phase starts, every volley of the
+    // phase gets its own rng from it
+    pub seed: u64,
+    pub volley: u64,
+}
+
+impl BossState {
+    pub fn new(phase: usize, definition: &BossDefinition, seed: u64) -> BossState {
+        BossState {
+            phase,
+            clock: 0.0,
+            transition: definition.phases[phase].transition.duration,
+            waypoint: 0,
+            timeline: 0.0,
+            fired: 0.0,
+            seed,
+            volley: 0,
+        }
+    }
+
+    pub fn invulnerable(&self) -> bool {
+        self.transition > 0.0
+    }
+
+    // moves the boss, nothing happens until the transition is over
+    pub fn update(&mut self, ship: &mut Ship, definition: &BossDefinition, dt: f32, width: f32) {
+        self.clock += dt;
+        if self.transition > 0.0 {
+            self.transition = (self.transition - dt).max(0.0);
+            return
+        }
+        self.timeline += dt;
+        definition.phases[self.phase].movement.apply(ship, &mut self.waypoint, dt, width);
+    }
+
+    // the bullets of the attacks that came due on the timeline, targets
+    // are the players that can be aimed at
+    pub fn fire(&mut self, ship: &Ship, definition: &BossDefinition, types: &BulletTypes, targets: &[Point]) -> Vec<Bullet> {
+        let mut bullets = Vec::new();
+        for (attack, fired) in definition.phases[self.phase].timeline.due(self.fired, self.timeline) {
+            let mut rng = Xoshiro256Plus::seed_from_u64(self.seed.wrapping_add(self.volley));
+            self.volley += 1;
+            let bullet_type = types.find(&attack.bullet).unwrap_or(BulletType::ENEMY);
+            bullets.extend(attack.fire(ship, bullet_type, targets, fired, &mut rng));
+        }
+        self.fired = self.timeline;
+        bullets
+    }
+
+    // the ship as it's drawn, None when it blinked out
+    pub fn animate(&self, ship: &Ship, definition: &BossDefinition) -> Option<Ship> {
+        let mut drawn = *ship;
+        if !self.invulnerable() {
+            return Some(drawn)
+        }
+        match definition.phases[self.phase].transition.animation {
+            Animation::Blink => {
+                if (self.transition * BOSS_BLINK_RATE) as u32 % 2 == 1 {
+                    return None
+                }
+            },
+            // unwinds back to where the boss faces
+            Animation::Spin => drawn.angle += self.transition * BOSS_SPIN_SPEED,
+        }
+        Some(drawn)
+    }
+}
+
+//=================
+//    MOVEMENT
+//=================
+
+impl Movement {
+    // waypoint is the point of a path the ship is heading to
+    pub fn apply(&self, ship: &mut Ship, waypoint: &mut usize, dt: f32, width: f32) {
+        match self {
+            Movement::Still => {},
+            Movement::Oscillate { speed } => {
+                if ship.pos.x <= SCREEN_BORDER {
+                    ship.direction = Some(1.0);
+                }
+                else if ship.pos.x >= width - SCREEN_BORDER {
+                    ship.direction = Some(-1.0);
+                }
+                ship.pos.x += dt * speed * ship.direction.unwrap_or(1.0);
+            },
+            Movement::Path { speed, points } => {
+                let mut step = dt * speed;
+                // a short path can be gone around more than once per step
+                for _ in 0..points.len() {
+                    let target = points[*waypoint % points.len()];
+                    let distance = crate::distance_2d(ship.pos, target);
+                    if distance > step {
+                        ship.pos.x += (target.x - ship.pos.x) / distance * step;
+                        ship.pos.y += (target.y - ship.pos.y) / distance * step;
+                        break
+                    }
+                    ship.pos = target;
+                    step -= distance;
+                    *waypoint = (*waypoint + 1) % points.len();
+                }
+            },
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if let Movement::Path { points, .. } = self {
+            if points.is_empty() {
+                return Err("a path needs at least one point")
+            }
+        }
+        Ok(())
+    }
+}
+
+//=================
+//     ERRORS
+//=================
+
+#[derive(Debug)]
+pub enum BossError {
+    Io(String, io::Error),
+    Parse(String),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for BossError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BossError::Io(path, e) => write!(f, "couldn't read {}: {}", path, e),
+            BossError::Parse(e) => write!(f, "invalid boss definition: {}", e),
+            BossError::Invalid(reason) => write!(f, "invalid boss definition: {}", reason),
+        }
+    }
+}
+
+impl Error for BossError {}
@@ -0,0 +1,200 @@
+use crate::constants::*;
+use crate::Point;
+
+use std::f32::consts;
+
+use serde::{Serialize, Deserialize};
+
+//=================
+//  BULLET TYPES
+//=================
+
+// Which kind of bullet is in flight, all that goes over the network. The
+// first ids are built in, a boss's own kinds come after them.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BulletType(pub u16);
+
+impl BulletType {
+    pub const NORMAL: BulletType = BulletType(0);
+    pub const SPECIAL: BulletType = BulletType(1);
+    pub const ENEMY: BulletType = BulletType(2);
+}
+
+// What every bullet of a kind does.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BulletKind {
+    // what attacks call it by
+    pub name: String,
+    #[serde(default = "enemy_sprite")]
+    pub sprite: String,
+    // pixels per second
+    pub speed: f32,
+    #[serde(default = "enemy_damage")]
+    pub damage: f32,
+    // added to the radius of the ship it hits
+    #[serde(default = "bullet_hitbox")]
+    pub hitbox: f32,
+    // seconds, None lasts until it leaves the screen
+    #[serde(default)]
+    pub lifetime: Option<f32>,
+    // ships it goes through before it's spent
+    #[serde(default)]
+    pub pierce: u32,
+    #[serde(default)]
+    pub motion: Motion,
+}
+
+fn enemy_sprite() -> String {
+    String::from("/enemy_bullet.png")
+}
+
+fn enemy_damage() -> f32 {
+    BOSS_BULLET_DAMAGE
+}
+
+fn bullet_hitbox() -> f32 {
+    BULLET_HITBOX
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum Motion {
+    #[default]
+    Linear,
+    // turns toward the closest target by up to `turn` degrees per second
+    Homing { turn: f32 },
+    // weaves `amplitude` pixels to each side, `frequency` times per second
+    Sine { amplitude: f32, frequency: f32 },
+    // pixels per second added every second
+    Accelerating { acceleration: f32 },
+    // slows down, stops after `after` seconds and comes back
+    Boomerang { after: f32 },
+}
+
+impl BulletKind {
+    fn builtin(name: &str, sprite: &str, speed: f32, damage: f32) -> BulletKind {
+        BulletKind {
+            name: name.to_string(),
+            sprite: sprite.to_string(),
+            speed,
+            damage,
+            hitbox: BULLET_HITBOX,
+            lifetime: None,
+            pierce: 0,
+            motion: Motion::Linear,
+        }
+    }
+
+    // age is how long the bullet has been moving, in seconds
+    pub fn speed_at(&self, age: f32) -> f32 {
+        match self.motion {
+            Motion::Accelerating { acceleration } => self.speed + acceleration * age,
+            Motion::Boomerang { after } => self.speed * (1.0 - age / after),
+            _ => self.speed,
+        }
+    }
+
+    // Moves a bullet going at `angle` that is `age` seconds old. Returns the
+    // new angle, homing bullets turn toward the closest target.
+    pub fn advance(&self, pos: &mut Point, angle: f32, age: f32, dt: f32, targets: &[Point]) -> f32 {
+        let mut angle = angle;
+        if let (Motion::Homing { turn }, Some(target)) = (self.motion, closest(*pos, targets)) {
+            // clockwise from straight up, like the ships
+            let wanted = (target.x - pos.x).atan2(pos.y - target.y);
+            let mut difference = (wanted - angle) % (2.0 * consts::PI);
+            if difference > consts::PI {
+                difference -= 2.0 * consts::PI;
+            }
+            else if difference < -consts::PI {
+                difference += 2.0 * consts::PI;
+            }
+            let max = turn.to_radians() * dt;
+            angle += difference.max(-max).min(max);
+        }
+
+        let step = self.speed_at(age) * dt;
+        pos.x += angle.sin() * step;
+        pos.y -= angle.cos() * step;
+
+        if let Motion::Sine { amplitude, frequency } = self.motion {
+            let phase = 2.0 * consts::PI * frequency;
+            let side = amplitude * ((phase * (age + dt)).sin() - (phase * age).sin());
+            pos.x += angle.cos() * side;
+            pos.y += angle.sin() * side;
+        }
+        angle
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(self.speed >= 0.0 && self.speed.is_finite()) {
+            return Err("a bullet speed can't be negative")
+        }
+        if !(self.damage >= 0.0 && self.damage.is_finite()) {
+            return Err("bullet damage can't be negative")
+        }
+        if !(self.hitbox >= 0.0 && self.hitbox.is_finite()) {
+            return Err("a bullet hitbox can't be negative")
+        }
+        if self.lifetime.is_some_and(|lifetime| !(lifetime > 0.0 && lifetime.is_finite())) {
+            return Err("a bullet lifetime has to be positive")
+        }
+        let valid = match self.motion {
+            Motion::Linear => true,
+            Motion::Homing { turn } => turn.is_finite(),
+            Motion::Sine { amplitude, frequency } => amplitude.is_finite() && frequency.is_finite(),
+            Motion::Accelerating { acceleration } => acceleration.is_finite(),
+            Motion::Boomerang { after } => after > 0.0 && after.is_finite(),
+        };
+        if !valid {
+            return Err("invalid bullet motion")
+        }
+        Ok(())
+    }
+}
+
+// a target that isn't a number can't be chased
+pub fn closest(pos: Point, targets: &[Point]) -> Option<Point> {
+    targets
+        .iter()
+        .copied()
+        .filter(|target| target.x.is_finite() && target.y.is_finite())
+        .min_by(|a, b| crate::distance_2d(pos, *a).total_cmp(&crate::distance_2d(pos, *b)))
+}
+
+// The kinds of bullets of a match, the built in ones then the boss's.
+// Every peer builds the same from the boss definition.
+#[derive(Clone, Debug)]
+pub struct BulletTypes {
+    kinds: Vec<BulletKind>,
+}
+
+impl BulletTypes {
+    pub fn new(custom: &[BulletKind]) -> BulletTypes {
+        let mut kinds = vec![
+            BulletKind::builtin("normal", "/player_bullet2.png", BULLET_SPEED, 1.0),
+            BulletKind::builtin("special", "/special_bullet.png", SPECIAL_BULLET_SPEED, SPECIAL_BULLET_DAMAGE),
+            BulletKind::builtin("enemy", "/enemy_bullet.png", BULLET_SPEED, BOSS_BULLET_DAMAGE),
+        ];
+        kinds.extend(custom.iter().cloned());
+        BulletTypes {
+            kinds,
+        }
+    }
+
+    // an id we don't know, from a peer with another boss, flies like an enemy bullet
+    pub fn get(&self, bullet_type: BulletType) -> &BulletKind {
+        self.kinds
+            .get(bullet_type.0 as usize)
+            .unwrap_or(&self.kinds[BulletType::ENEMY.0 as usize])
+    }
+
+    pub fn find(&self, name: &str) -> Option<BulletType> {
+        self.kinds
+            .iter()
+            .position(|kind| kind.name == name)
+            .map(|i| BulletType(i as u16))
+    }
+
+    pub fn is_builtin(name: &str) -> bool {
+        BulletTypes::new(&[]).find(name).is_some()
+    }
+}
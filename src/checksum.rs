@@ -0,0 +1,228 @@
+use crate::constants::*;
+use crate::simulation::Simulation;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Serialize, Deserialize};
+
+use uuid::Uuid;
+
+//=================
+//    CHECKSUM
+//=================
+
+// One hash per part of the state rather than a single one, so a mismatch
+// tells us what diverged and not only that something did.
+#[derive(Clone, Debug)]
+pub struct Checksum {
+    pub frame: u32,
+    pub fields: Vec<(String, u64)>,
+}
+
+// What the other peers get: the names stay here, every field is known by a
+// hash of its name. It has to fit in one packet however many players there
+// are, so the fields past MAX_DIGEST_FIELDS are only part of the total.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Digest {
+    pub frame: u32,
+    pub total: u64,
+    pub fields: Vec<(u32, u64)>,
+}
+
+impl Checksum {
+    pub fn of(sim: &Simulation, frame: u32) -> Checksum {
+        // the ships last, the ones past the digest's fields are the least
+        // useful to name
+        let mut fields = vec![
+            (String::from("boss pos"), hash(&sim.enemy_ship.pos)),
+            (String::from("boss health"), hash(&sim.enemy_ship.health)),
+            (String::from("boss direction"), hash(&sim.enemy_ship.direction)),
+            (String::from("boss phase"), hash(&sim.boss_state())),
+            (String::from("wave"), hash(&sim.wave())),
+            (String::from("enemies"), hash(&sim.enemies)),
+            (String::from("bullet count"), sim.bullets.len() as u64),
+            (String::from("bullets"), hash(&sim.bullets)),
+            (String::from("rng"), hash(&sim.rng)),
+            (String::from("time"), hash(&sim.time())),
+        ];
+
+        let mut ships: Vec<_> = sim.player_ship.iter().chain(sim.other_players.iter()).collect();
+        ships.sort_by_key(|ship| ship.id);
+        for ship in ships {
+            let name = short_id(ship.id);
+            fields.push((format!("{} pos", name), hash(&ship.pos)));
+            fields.push((format!("{} health", name), hash(&ship.health)));
+            fields.push((format!("{} shield", name), hash(&ship.shield)));
+            fields.push((format!("{} cooldowns", name), hash(&sim.cooldowns.get(&ship.id))));
+        }
+
+        Checksum {
+            frame,
+            fields,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        hash(&self.fields)
+    }
+
+    pub fn digest(&self) -> Digest {
+        Digest {
+            frame: self.frame,
+            total: self.total(),
+            fields: self.fields
+                .iter()
+                .take(MAX_DIGEST_FIELDS)
+                .map(|(name, value)| (key(name), *value))
+                .collect(),
+        }
+    }
+
+    // Names of the fields that differ or only exist on one side. The ones
+    // only the other peer has can't be named, their key is given instead.
+    pub fn diff(&self, other: &Digest) -> Vec<String> {
+        let ours: BTreeMap<u32, (&String, u64)> = self.fields
+            .iter()
+            .take(MAX_DIGEST_FIELDS)
+            .map(|(name, value)| (key(name), (name, *value)))
+            .collect();
+        let theirs: BTreeMap<u32, u64> = other.fields.iter().copied().collect();
+
+        let mut names: Vec<String> = ours
+            .iter()
+            .filter(|(key, (_, value))| theirs.get(key) != Some(value))
+            .map(|(_, (name, _))| name.to_string())
+            .chain(theirs
+                .keys()
+                .filter(|key| !ours.contains_key(key))
+                .map(|key| format!("field {:08x}", key)))
+            .collect();
+        names.sort();
+        if names.is_empty() {
+            names.push(String::from("fields past the digest"));
+        }
+        names
+    }
+}
+
+// FNV-1a over the serialized value. The std hasher isn't guaranteed to give
+// the same result on every build, this is.
+fn hash<T: Serialize>(value: &T) -> u64 {
+    let bytes = bincode::serialize(value).unwrap();
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// what a field is known by in a digest
+fn key(name: &str) -> u32 {
+    hash(&name) as u32
+}
+
+fn short_id(id: Uuid) -> String {
+    id.to_simple().to_string()[..8].to_string()
+}
+
+//=================
+//    DETECTOR
+//=================
+
+struct Record {
+    checksum: Checksum,
+    // what gets written to disk if this frame turns out to be desynced
+    dump: Option<String>,
+}
+
+// Keeps our recent checksums around until the other peers' ones for the
+// same frames arrive, they can be a bit behind or ahead of us.
+pub struct DesyncDetector {
+    dump_dir: Option<PathBuf>,
+    local: BTreeMap<u32, Record>,
+    remote: BTreeMap<u32, Vec<(Uuid, Digest)>>,
+    dumped: bool,
+}
+
+impl DesyncDetector {
+    pub fn new(dump_dir: Option<PathBuf>) -> DesyncDetector {
+        DesyncDetector {
+            dump_dir,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            dumped: false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.local.clear();
+        self.remote.clear();
+        self.dumped = false;
+    }
+
+    // called after every simulated frame, returns the digest to send to
+    // the other peers on the frames that get checked
+    pub fn record(&mut self, sim: &Simulation, frame: u32) -> Option<Digest> {
+        if !frame.is_multiple_of(CHECKSUM_INTERVAL) {
+            return None
+        }
+
+        let checksum = Checksum::of(sim, frame);
+        let dump = self.dump_dir.as_ref().map(|_| dump_state(sim));
+        let digest = checksum.digest();
+        let record = Record {
+            checksum,
+            dump,
+        };
+
+        for (id, remote) in self.remote.remove(&frame).unwrap_or_default() {
+            compare(&self.dump_dir, &mut self.dumped, &record, id, &remote);
+        }
+        self.local.insert(frame, record);
+
+        // old frames can't be compared anymore
+        while self.local.len() > CHECKSUM_HISTORY {
+            self.local.pop_first();
+        }
+        let oldest = self.local.keys().next().copied().unwrap_or(0);
+        self.remote.retain(|&frame, _| frame >= oldest);
+
+        Some(digest)
+    }
+
+    pub fn receive(&mut self, id: Uuid, digest: Digest) {
+        if let Some(record) = self.local.get(&digest.frame) {
+            compare(&self.dump_dir, &mut self.dumped, record, id, &digest);
+        }
+        else if self.local.keys().next().is_none_or(|&oldest| digest.frame > oldest) {
+            self.remote.entry(digest.frame).or_default().push((id, digest));
+        }
+    }
+}
+
+// logs the fields that differ. Only the first desync gets dumped, everything
+// after it differs anyway. The other peer dumps its own state on its side.
+fn compare(dump_dir: &Option<PathBuf>, dumped: &mut bool, record: &Record, id: Uuid, remote: &Digest) {
+    let local = &record.checksum;
+    if local.total() == remote.total {
+        return
+    }
+
+    let fields = local.diff(remote);
+    eprintln!("desync at frame {} with {}: {}", local.frame, id, fields.join(", "));
+
+    if let (Some(dir), Some(state), false) = (dump_dir, &record.dump, *dumped) {
+        *dumped = true;
+        let path = dir.join(format!("desync-{}-{}.txt", local.frame, short_id(id)));
+        let contents = format!("differing fields: {}\n\nlocal: {:#?}\n\nremote ({}): {:#?}\n\nstate: {}\n",
+                               fields.join(", "), local, id, remote, state);
+        match fs::create_dir_all(dir).and_then(|_| fs::write(&path, contents)) {
+            Ok(()) => eprintln!("state dumped to {}", path.display()),
+            Err(e) => eprintln!("couldn't dump the state to {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn dump_state(sim: &Simulation) -> String {
+    format!("{:#?}", (&sim.player_ship, &sim.other_players, &sim.enemy_ship, &sim.enemies, &sim.bullets, &sim.rng, &sim.cooldowns))
+}
@@ -0,0 +1,335 @@
+use crate::constants::{ HOST_PORT, PEER_PORT, INTERPOLATION_DELAY, LOCKSTEP_INPUT_DELAY, PEER_TIMEOUT };
+use crate::network::Network;
+use crate::netsim::Conditions;
+use crate::transport::TransportKind;
+
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+
+pub const USAGE: &str = "\
+USAGE:
+    orchid [OPTIONS] [HOST_ADDRESS]
+
+OPTIONS:
+    --host              host a match (default)
+    --join <addr>       join the match hosted at <addr> (ip:port, the port defaults to 7777)
+    --lan               list the games hosted on the local network and pick one to join
+    --bind <ip>         address to bind the socket to (default 0.0.0.0)
+    --port <port>       port to bind the socket to (default 7777 when hosting, 7778 when joining)
+    --websocket         talk over WebSocket instead of UDP, every peer has to pass it
+    --name <name>       name shown to the other players
+    --player-id <uuid>  id of your ship, rejoining with it gives you the ship back (remembered per name)
+    --seed <seed>       seed for the boss rng (hosts only)
+    --level <name>      waves to fight before their boss, from resources/levels/<name>.ron (hosts only, default garden)
+    --boss <name>       fight only this boss, from resources/bosses/<name>.ron (hosts only)
+    --interp-delay <ms> how far in the past remote ships are drawn (default 100)
+    --lockstep          run every player's simulation from exchanged inputs, every peer has to pass it
+    --input-delay <n>   frames before a lockstep input is simulated (default 3)
+    --timeout <secs>    drop peers that were silent for this long (default 5)
+    --desync-dump <dir> write the state to <dir> when a lockstep peer diverges
+    --latency <ms>      simulate a slow network, added to every packet both ways (F1 in game)
+    --jitter <ms>       simulated latency varies by up to this much
+    --loss <pct>        simulated packet loss, in percent
+    --duplicate <pct>   simulated duplicated packets, in percent
+    --reorder <pct>     simulated packets arriving out of order, in percent
+    --stats <secs>      log the network stats every <secs> (dedicated server only, F12 in game)
+    -h, --help          print this message";
+
+//=================
+//     CONFIG
+//=================
+
+#[derive(Debug, Clone, Copy)]
+pub enum Mode {
+    Host,
+    Join(SocketAddr),
+    // pick a host among the ones found on the LAN
+    Browse,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub mode: Mode,
+    pub bind: IpAddr,
+    pub port: Option<u16>,
+    pub name: String,
+    // the id our ship keeps across runs, to rejoin a match
+    pub player_id: Option<Uuid>,
+    pub seed: Option<u64>,
+    // file names, without the extension. A boss picked on its own is
+    // fought without the level's waves
+    pub level: String,
+    pub boss: Option<String>,
+    // in seconds
+    pub interp_delay: f32,
+    pub lockstep: bool,
+    // in lockstep frames
+    pub input_delay: u32,
+    pub desync_dump: Option<PathBuf>,
+    // in seconds
+    pub timeout: f32,
+    pub transport: TransportKind,
+    // fake network problems, for testing
+    pub conditions: Conditions,
+    // in seconds
+    pub stats_interval: Option<f32>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            mode: Mode::Host,
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: None,
+            name: String::from("player"),
+            player_id: None,
+            seed: None,
+            level: String::from("garden"),
+            boss: None,
+            interp_delay: INTERPOLATION_DELAY,
+            lockstep: false,
+            input_delay: LOCKSTEP_INPUT_DELAY,
+            desync_dump: None,
+            timeout: PEER_TIMEOUT,
+            transport: TransportKind::Udp,
+            conditions: Conditions::default(),
+            stats_interval: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn network_type(&self) -> Network {
+        match self.mode {
+            Mode::Host => Network::Host,
+            Mode::Join(_) | Mode::Browse => Network::Peer,
+        }
+    }
+
+    pub fn bind_address(&self) -> SocketAddr {
+        let default_port = match self.mode {
+            Mode::Host => HOST_PORT,
+            Mode::Join(_) | Mode::Browse => PEER_PORT,
+        };
+        SocketAddr::new(self.bind, self.port.unwrap_or(default_port))
+    }
+}
+
+//=================
+//     ERRORS
+//=================
+
+#[derive(Debug)]
+pub enum CliError {
+    Help,
+    UnknownFlag(String),
+    MissingValue(&'static str),
+    InvalidValue(&'static str, String),
+    Conflict(&'static str, &'static str),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Help => write!(f, "help requested"),
+            CliError::UnknownFlag(flag) => write!(f, "unknown argument '{}'", flag),
+            CliError::MissingValue(flag) => write!(f, "{} expects a value", flag),
+            CliError::InvalidValue(flag, value) => write!(f, "invalid value '{}' for {}", value, flag),
+            CliError::Conflict(a, b) => write!(f, "{} can't be used with {}", a, b),
+        }
+    }
+}
+
+impl Error for CliError {}
+
+//=================
+//     PARSING
+//=================
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Config, CliError> {
+    let mut config = Config::default();
+    let mut host = false;
+    let mut join = false;
+    let mut lan = false;
+    let mut level = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Err(CliError::Help),
+            "--host" => host = true,
+            "--join" => {
+                let value = next_value(&mut args, "--join")?;
+                config.mode = Mode::Join(parse_address(&value)?);
+                join = true;
+            },
+            "--lan" => {
+                config.mode = Mode::Browse;
+                lan = true;
+            },
+            "--bind" => {
+                let value = next_value(&mut args, "--bind")?;
+                config.bind = value.parse()
+                    .map_err(|_| CliError::InvalidValue("--bind", value))?;
+            },
+            "--port" => {
+                let value = next_value(&mut args, "--port")?;
+                config.port = Some(value.parse()
+                    .map_err(|_| CliError::InvalidValue("--port", value))?);
+            },
+            "--name" => {
+                let value = next_value(&mut args, "--name")?;
+                if value.trim().is_empty() {
+                    return Err(CliError::InvalidValue("--name", value))
+                }
+                config.name = value;
+            },
+            "--player-id" => {
+                let value = next_value(&mut args, "--player-id")?;
+                config.player_id = Some(Uuid::parse_str(&value)
+                    .map_err(|_| CliError::InvalidValue("--player-id", value))?);
+            },
+            "--seed" => {
+                let value = next_value(&mut args, "--seed")?;
+                config.seed = Some(value.parse()
+                    .map_err(|_| CliError::InvalidValue("--seed", value))?);
+            },
+            "--level" => {
+                config.level = parse_name(next_value(&mut args, "--level")?, "--level")?;
+                level = true;
+            },
+            "--boss" => config.boss = Some(parse_name(next_value(&mut args, "--boss")?, "--boss")?),
+            "--interp-delay" => {
+                let value = next_value(&mut args, "--interp-delay")?;
+                let delay: f32 = value.parse()
+                    .map_err(|_| CliError::InvalidValue("--interp-delay", value.clone()))?;
+                if !(0.0..=1000.0).contains(&delay) {
+                    return Err(CliError::InvalidValue("--interp-delay", value))
+                }
+                config.interp_delay = delay / 1000.0;
+            },
+            "--stats" => {
+                let value = next_value(&mut args, "--stats")?;
+                let interval: f32 = value.parse()
+                    .map_err(|_| CliError::InvalidValue("--stats", value.clone()))?;
+                if !(interval > 0.0 && interval <= 3600.0) {
+                    return Err(CliError::InvalidValue("--stats", value))
+                }
+                config.stats_interval = Some(interval);
+            },
+            "--websocket" => config.transport = TransportKind::WebSocket,
+            "--lockstep" => config.lockstep = true,
+            "--input-delay" => {
+                let value = next_value(&mut args, "--input-delay")?;
+                let delay: u32 = value.parse()
+                    .map_err(|_| CliError::InvalidValue("--input-delay", value.clone()))?;
+                if delay > 60 {
+                    return Err(CliError::InvalidValue("--input-delay", value))
+                }
+                config.input_delay = delay;
+            },
+            "--timeout" => {
+                let value = next_value(&mut args, "--timeout")?;
+                let timeout: f32 = value.parse()
+                    .map_err(|_| CliError::InvalidValue("--timeout", value.clone()))?;
+                if !(timeout > 0.0 && timeout <= 600.0) {
+                    return Err(CliError::InvalidValue("--timeout", value))
+                }
+                config.timeout = timeout;
+            },
+            "--desync-dump" => {
+                let value = next_value(&mut args, "--desync-dump")?;
+                config.desync_dump = Some(PathBuf::from(value));
+            },
+            "--latency" => config.conditions.latency = parse_millis(next_value(&mut args, "--latency")?, "--latency")?,
+            "--jitter" => config.conditions.jitter = parse_millis(next_value(&mut args, "--jitter")?, "--jitter")?,
+            "--loss" => config.conditions.loss = parse_percent(next_value(&mut args, "--loss")?, "--loss")?,
+            "--duplicate" => config.conditions.duplicate = parse_percent(next_value(&mut args, "--duplicate")?, "--duplicate")?,
+            "--reorder" => config.conditions.reorder = parse_percent(next_value(&mut args, "--reorder")?, "--reorder")?,
+            // kept so `orchid <host address>` still works
+            _ if !arg.starts_with('-') && !join => {
+                config.mode = Mode::Join(parse_address(&arg)?);
+                join = true;
+            },
+            _ => return Err(CliError::UnknownFlag(arg)),
+        }
+    }
+
+    if host && join {
+        return Err(CliError::Conflict("--host", "--join"))
+    }
+    if join && config.seed.is_some() {
+        return Err(CliError::Conflict("--seed", "--join"))
+    }
+    if lan && (host || join) {
+        return Err(CliError::Conflict("--lan", if host { "--host" } else { "--join" }))
+    }
+    if lan && config.seed.is_some() {
+        return Err(CliError::Conflict("--seed", "--lan"))
+    }
+    // the host picks the level and the boss
+    if level && config.boss.is_some() {
+        return Err(CliError::Conflict("--level", "--boss"))
+    }
+    if (level || config.boss.is_some()) && (join || lan) {
+        let flag = if level { "--level" } else { "--boss" };
+        return Err(CliError::Conflict(flag, if join { "--join" } else { "--lan" }))
+    }
+    // the beacon only knows about UDP games
+    if lan && config.transport == TransportKind::WebSocket {
+        return Err(CliError::Conflict("--websocket", "--lan"))
+    }
+
+    Ok(config)
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, flag: &'static str) -> Result<String, CliError> {
+    match args.next() {
+        Some(value) if !value.starts_with("--") => Ok(value),
+        _ => Err(CliError::MissingValue(flag)),
+    }
+}
+
+// a file name in the resources, not a path
+fn parse_name(value: String, flag: &'static str) -> Result<String, CliError> {
+    if value.is_empty() || value.contains(['/', '\\', '.']) {
+        return Err(CliError::InvalidValue(flag, value))
+    }
+    Ok(value)
+}
+
+// milliseconds between 0 and 5000, returned in seconds
+fn parse_millis(value: String, flag: &'static str) -> Result<f32, CliError> {
+    match value.parse::<f32>() {
+        Ok(ms) if (0.0..=5000.0).contains(&ms) => Ok(ms / 1000.0),
+        _ => Err(CliError::InvalidValue(flag, value)),
+    }
+}
+
+// a percentage, returned between 0 and 1
+fn parse_percent(value: String, flag: &'static str) -> Result<f32, CliError> {
+    match value.parse::<f32>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent / 100.0),
+        _ => Err(CliError::InvalidValue(flag, value)),
+    }
+}
+
+// accepts ip:port, hostname:port or a bare ip/hostname on the default port
+fn parse_address(value: &str) -> Result<SocketAddr, CliError> {
+    let with_port = if value.contains(':') {
+        value.to_string()
+    }
+    else {
+        format!("{}:{}", value, HOST_PORT)
+    };
+
+    with_port
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.find(|addr| addr.is_ipv4()))
+        .ok_or_else(|| CliError::InvalidValue("--join", value.to_string()))
+}
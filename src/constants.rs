@@ -1,22 +1,103 @@
 use ggez::graphics;
 use crate::Point;
+use std::time::Duration;
 
-pub const PEER_PORT: &str = "7778";
-pub const HOST_PORT: &str = "7777";
+pub const PEER_PORT: u16 = 7778;
+pub const HOST_PORT: u16 = 7777;
+// hosts answer LAN discovery probes on this one
+pub const DISCOVERY_PORT: u16 = 7776;
 
 pub const SHIP_SPEED: f32 = 350.0;
-pub const BOSS_SPEED: f32 = 125.0;
+pub const PLAYER_HEALTH: f32 = 1.0;
 pub const BULLET_SPEED: f32 = 500.0;
 pub const PLAYER_FIRE_RATE: f64 = 200.0;
-pub const BOSS_FIRE_RATE: f64 = 250.0;
 pub const SPECIAL_BULLET_SPEED: f32 = 250.0;
 pub const SPECIAL_BULLET_COOLDOWN: f32 = 5.0;
 pub const SPECIAL_BULLET_DAMAGE: f32 = 5.0;
+pub const BOSS_BULLET_DAMAGE: f32 = 2.0;
+// a bullet hits when it's closer to a ship than both hitboxes
+pub const BULLET_HITBOX: f32 = 4.0;
+pub const PLAYER_HITBOX: f32 = 20.0;
 pub const GREEN: graphics::Color = graphics::Color::new(0.0, 255.0, 0.0, 1.0);
 pub const RED: graphics::Color = graphics::Color::new(255.0, 0.0, 0.0, 1.0);
 pub const SCREEN_BORDER: f32 = 20.0;
 pub const SHIELD_COOLDOWN: f32 = 15.0;
 pub const SHIELD_DURATION: f32 = 2.0;
-pub const BOSS_HEALTH: f32 = 100.0;
 pub const BROADCAST_TICK: f32 = 1.0/30.0;
 pub const PLAYER_SPAWN: Point = Point{ x: 400.0, y: 500.0};
+// default window size, used until the real drawable size is known
+pub const SCREEN_WIDTH: f32 = 800.0;
+pub const SCREEN_HEIGHT: f32 = 600.0;
+pub const SERVER_TICK: f32 = 1.0/60.0;
+pub const RELIABLE_RESEND: f32 = 0.2;
+// a peer that didn't ack a message after that many resends is gone
+pub const RELIABLE_MAX_RESENDS: u32 = 25;
+// reliable messages kept at most per channel while waiting for the ones
+// before them, those further ahead are dropped unacked and come again
+pub const RELIABLE_RECEIVE_WINDOW: u32 = 1024;
+pub const INPUT_REDUNDANCY: usize = 8;
+pub const MAX_PENDING_INPUTS: usize = 120;
+// longest frame the server accepts from a client input
+pub const MAX_INPUT_DT: f32 = 0.1;
+pub const INTERPOLATION_DELAY: f32 = 0.1;
+pub const MAX_EXTRAPOLATION: f32 = 0.25;
+// seconds the extrapolated offset takes to shrink back to the last position
+pub const EXTRAPOLATION_DECAY: f32 = 0.25;
+pub const LOCKSTEP_DT: f32 = 1.0/60.0;
+// frames between reading an input and simulating it, hides the latency
+pub const LOCKSTEP_INPUT_DELAY: u32 = 3;
+// frames simulated at most in one update after a stall
+pub const LOCKSTEP_MAX_CATCHUP: u32 = 8;
+// lockstep frames between two state checksums
+pub const CHECKSUM_INTERVAL: u32 = 30;
+// checksums kept around waiting for the other peers' ones
+pub const CHECKSUM_HISTORY: usize = 16;
+// field hashes sent with a checksum, the others only count in its total
+pub const MAX_DIGEST_FIELDS: usize = 48;
+pub const HEARTBEAT_INTERVAL: f32 = 0.5;
+pub const PEER_TIMEOUT: f32 = 5.0;
+// how long "player left" and the like stay on screen
+pub const NOTICE_DURATION: f32 = 3.0;
+// weight of a new sample in the smoothed round trip time
+pub const RTT_SMOOTHING: f32 = 0.125;
+// seconds between two discovery probes
+pub const DISCOVERY_INTERVAL: f32 = 1.0;
+// leeway given to the players by the server's validation
+pub const MAX_SAVED_SHOTS: f32 = 3.0;
+pub const COOLDOWN_TOLERANCE: f32 = 0.9;
+pub const MOVEMENT_TOLERANCE: f32 = 1.5;
+pub const MOVEMENT_SLACK: f32 = 0.1;
+// seconds of input a player can get ahead of the server's clock
+pub const MAX_INPUT_BACKLOG: f32 = 0.5;
+pub const MAX_BULLET_DISTANCE: f32 = 120.0;
+pub const VIOLATION_LOG_INTERVAL: f32 = 1.0;
+// network simulator: extra delay of a reordered packet, and the steps of the
+// debug panel
+pub const REORDER_DELAY: f32 = 0.1;
+pub const NETSIM_DELAY_STEP: f32 = 0.025;
+pub const NETSIM_CHANCE_STEP: f32 = 0.05;
+// a websocket peer that can't be reached isn't tried again for a while,
+// connecting blocks the game for up to the handshake timeout
+pub const WEBSOCKET_HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+pub const WEBSOCKET_RETRY: f32 = 1.0;
+// snapshot positions are rounded to 1/8 of a pixel
+pub const SNAPSHOT_PRECISION: f32 = 8.0;
+// snapshots kept as baselines for the next deltas
+pub const SNAPSHOT_HISTORY: usize = 32;
+// seconds over which the network stats are averaged
+pub const STATS_WINDOW: f32 = 1.0;
+// blinks per second of a boss in transition
+pub const BOSS_BLINK_RATE: f32 = 10.0;
+// how fast a boss spins in transition, radians per second
+pub const BOSS_SPIN_SPEED: f32 = 4.0 * std::f32::consts::PI;
+// bullets one firing of a pattern can have
+pub const MAX_PATTERN_BULLETS: u32 = 64;
+// firings of one cue per lap of its timeline, they're all looked at every frame
+pub const MAX_CUE_TIMES: u32 = 100;
+// a boss or a level definition has to fit in one packet
+pub const MAX_BOSS_SIZE: u64 = 1000;
+pub const MAX_LEVEL_SIZE: u64 = 1000;
+// keeps the world of a match in progress in one packet too
+pub const MAX_WAVE_ENEMIES: usize = 6;
+// ships sent with the world, the others follow in chunks of as many
+pub const MAX_WORLD_SHIPS: usize = 8;
@@ -0,0 +1,149 @@
+use crate::constants::*;
+
+use std::io;
+use std::net::{UdpSocket, SocketAddr, Ipv4Addr};
+
+use serde::{Serialize, Deserialize};
+
+//=================
+//    PROTOCOL
+//=================
+
+// Discovery doesn't go through the game protocol: a client has to be able
+// to list hosts that speak another version, if only to say so.
+const PROBE: [u8; 5] = *b"ORCH?";
+const ANSWER: [u8; 5] = *b"ORCH!";
+
+// what a host tells the clients looking for a game
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    pub version: u16,
+    pub name: String,
+    pub players: u8,
+    pub in_progress: bool,
+    // port of the game socket, the beacon has its own
+    pub port: u16,
+}
+
+//=================
+//     BEACON
+//=================
+
+// answers the probes of the clients browsing the LAN
+pub struct Beacon {
+    socket: UdpSocket,
+}
+
+impl Beacon {
+    // only one host per machine can get the discovery port
+    pub fn bind() -> io::Result<Beacon> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        socket.set_nonblocking(true)?;
+        Ok(Beacon { socket })
+    }
+
+    pub fn answer(&self, announcement: &Announcement) {
+        let mut buf = [0u8; PROBE.len()];
+        while let Ok((amt, src)) = self.socket.recv_from(&mut buf) {
+            if buf[..amt] != PROBE {
+                continue
+            }
+
+            let mut data = ANSWER.to_vec();
+            data.extend(bincode::serialize(announcement).unwrap());
+            let _ = self.socket.send_to(&data, src);
+        }
+    }
+}
+
+//=================
+//    BROWSER
+//=================
+
+pub struct Game {
+    pub addr: SocketAddr,
+    pub announcement: Announcement,
+    // seconds since it last answered
+    silence: f32,
+}
+
+// Broadcasts a probe every now and then and keeps a list of the hosts
+// that answered, forgetting the ones that stopped.
+pub struct Browser {
+    socket: UdpSocket,
+    probe_timer: f32,
+    games: Vec<Game>,
+    selected: usize,
+}
+
+impl Browser {
+    pub fn new() -> io::Result<Browser> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(Browser {
+            socket,
+            probe_timer: 0.0,
+            games: Vec::new(),
+            selected: 0,
+        })
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.probe_timer -= dt;
+        if self.probe_timer < 0.0 {
+            // a network without broadcast just never answers
+            let _ = self.socket.send_to(&PROBE, (Ipv4Addr::BROADCAST, DISCOVERY_PORT));
+            self.probe_timer = DISCOVERY_INTERVAL;
+        }
+
+        for game in &mut self.games {
+            game.silence += dt;
+        }
+
+        let mut buf = [0u8; 512];
+        while let Ok((amt, src)) = self.socket.recv_from(&mut buf) {
+            if amt < ANSWER.len() || buf[..ANSWER.len()] != ANSWER {
+                continue
+            }
+            let announcement: Announcement = match bincode::deserialize(&buf[ANSWER.len()..amt]) {
+                Ok(announcement) => announcement,
+                Err(_) => continue,
+            };
+
+            let addr = SocketAddr::new(src.ip(), announcement.port);
+            match self.games.iter_mut().find(|game| game.addr == addr) {
+                Some(game) => {
+                    game.announcement = announcement;
+                    game.silence = 0.0;
+                },
+                None => self.games.push(Game { addr, announcement, silence: 0.0 }),
+            }
+        }
+
+        self.games.retain(|game| game.silence < DISCOVERY_INTERVAL * 3.0);
+        self.selected = self.selected.min(self.games.len().saturating_sub(1));
+    }
+
+    pub fn games(&self) -> &[Game] {
+        &self.games
+    }
+
+    pub fn selected(&self) -> Option<&Game> {
+        self.games.get(self.selected)
+    }
+
+    pub fn selected_index(&self) -> usize {
+        self.selected
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.games.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+}
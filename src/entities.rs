@@ -1,7 +1,10 @@
 use crate::constants::*;
 use crate::assets::Assets;
 use crate::Point;
-use crate::states::InputState;
+use crate::simulation::InputState;
+use crate::boss::BossDefinition;
+use crate::level::EnemyKind;
+use crate::bullets::{ BulletType, BulletKind };
 
 use uuid::Uuid;
 
@@ -9,8 +12,6 @@ use serde::{Serialize, Deserialize};
 
 use std::f32::consts;
 
-use mint;
-
 use ggez::{graphics, Context, GameResult};
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
@@ -31,15 +32,16 @@ pub struct Bullet {
     pub hit: bool,
     pub bullet_type: BulletType,
     pub id: Option<u64>,
+    // seconds before it starts moving
+    pub delay: f32,
+    // seconds it has been moving for
+    pub age: f32,
+    // ships it went through
+    pub pierced: u32,
+    // the last ship it hit, not hit again while going through it
+    pub last_hit: Option<Uuid>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
-pub enum BulletType {
-    Normal,
-    Special,
-}
-
-
 impl Bullet {
     pub fn new(possession: Possession, angle: f32, pos: Point, bullet_type: BulletType, id: Option<u64>) -> Bullet {
         let new_pos = Point{
@@ -49,44 +51,56 @@ impl Bullet {
             y: pos.y - (consts::PI/2.0-angle).sin()*20.0,
         };
         Bullet {
-            possession: possession,
-            angle: angle,
+            possession,
+            angle,
             pos: new_pos,
             hit: false,
-            bullet_type: bullet_type,
-            id: id 
+            bullet_type,
+            id,
+            delay: 0.0,
+            age: 0.0,
+            pierced: 0,
+            last_hit: None,
         }
     }
 
-    pub fn draw(&self, assets: &mut Assets, ctx: &mut Context) -> GameResult {
+    // as it leaves the ship, what players send has to be
+    pub fn is_fresh(&self) -> bool {
+        self.delay == 0.0 && self.age == 0.0 && self.pierced == 0 && self.last_hit.is_none()
+    }
 
-        let img = match self.possession {
-            Possession::Player => match self.bullet_type {
-                BulletType::Normal => &assets.player_bullet,
-                BulletType::Special => &assets.special_bullet,
-            }
-            Possession::Enemy => &assets.enemy_bullet,
-        };
+    pub fn draw(&self, kind: &BulletKind, assets: &mut Assets, ctx: &mut Context) -> GameResult {
+        let img = assets.sprite(ctx, &kind.sprite).cloned().unwrap_or_else(|| assets.enemy_bullet.clone());
 
         let drawparams = graphics::DrawParam::new()
             .dest(mint::Point2{ x: self.pos.x, y: self.pos.y })
             .offset(mint::Point2{ x:0.5, y:0.5 });
 
-        graphics::draw(ctx,img,drawparams)
+        graphics::draw(ctx,&img,drawparams)
     }
 
-    pub fn update_pos(&mut self, dt: f32) {
-        match self.bullet_type {
-            // if more bullet types are added, make a bullet_type struct instead to avoid copy paste
-            BulletType::Normal => {
-                self.pos.x += (consts::PI/2.0-self.angle).cos() * dt * BULLET_SPEED;
-                self.pos.y -= (consts::PI/2.0-self.angle).sin() * dt * BULLET_SPEED;
-            }
-            BulletType::Special => {
-                self.pos.x += (consts::PI/2.0-self.angle).cos() * dt * SPECIAL_BULLET_SPEED;
-                self.pos.y -= (consts::PI/2.0-self.angle).sin() * dt * SPECIAL_BULLET_SPEED;
-            }
+    // targets are what a homing bullet can go after
+    pub fn update_pos(&mut self, kind: &BulletKind, dt: f32, targets: &[Point]) {
+        if self.delay > 0.0 {
+            self.delay -= dt;
+            return
+        }
+        self.angle = kind.advance(&mut self.pos, self.angle, self.age, dt, targets);
+        self.age += dt;
+    }
+
+    pub fn expired(&self, kind: &BulletKind) -> bool {
+        kind.lifetime.is_some_and(|lifetime| self.age >= lifetime)
+    }
 
+    // it hit that ship, it's spent unless it can go through one more
+    pub fn strike(&mut self, id: Uuid, kind: &BulletKind) {
+        self.last_hit = Some(id);
+        if self.pierced >= kind.pierce {
+            self.hit = true;
+        }
+        else {
+            self.pierced += 1;
         }
     }
 }
@@ -111,8 +125,8 @@ impl Ship {
         match ship_type {
             Possession::Player => {
                 Ship {
-                    health: 1.0,
-                    ship_type: ship_type,
+                    health: PLAYER_HEALTH,
+                    ship_type,
                     pos: PLAYER_SPAWN,
                     angle: 0.0,
                     direction: None,
@@ -120,34 +134,46 @@ impl Ship {
                     id: Uuid::new_v4(),
                 }
             }
-            Possession::Enemy => {
-                Ship {
-                    health: BOSS_HEALTH,
-                    ship_type: ship_type,
-                    pos: Point{ x:400.0, y:50.0 },
-                    angle: consts::PI,
-                    direction: Some(1.0),
-                    shield: false,
-                    id: Uuid::new_v4(),
-                }
-            }
+            Possession::Enemy => Ship::boss(&BossDefinition::default()),
+        }
+    }
+
+    pub fn boss(definition: &BossDefinition) -> Ship {
+        Ship {
+            health: definition.health,
+            ship_type: Possession::Enemy,
+            pos: definition.spawn,
+            angle: consts::PI,
+            direction: Some(1.0),
+            shield: false,
+            // every peer has to agree on the boss id
+            id: Uuid::nil(),
+        }
+    }
+
+    // a regular enemy, coming before the boss
+    pub fn enemy(kind: &EnemyKind, pos: Point, id: Uuid) -> Ship {
+        Ship {
+            health: kind.health,
+            ship_type: Possession::Enemy,
+            pos,
+            angle: consts::PI,
+            direction: Some(1.0),
+            shield: false,
+            id,
         }
     }
 
     pub fn reset(&mut self) {
         match self.ship_type {
             Possession::Player => {
-                self.health = 1.0;
+                self.health = PLAYER_HEALTH;
                 self.pos = PLAYER_SPAWN;
                 self.shield = false;
             
             },
-            Possession::Enemy => {
-                self.health = BOSS_HEALTH;
-                self.pos = Point{ x:400.0, y:50.0};
-                self.shield = false;
-                self.direction = Some(1.0);
-            },
+            // respawned from its definition, see Simulation::reset
+            Possession::Enemy => {},
 
         }
 
@@ -161,17 +187,15 @@ impl Ship {
     // the optionnal argument lets ships shoot in more directions
     // i.e bosses can shoot in diagonals
     pub fn shoot(&self, curve: Option<f32>, bullet_type: BulletType, id: Option<u64>) -> Bullet {
-        match curve {
-            Some(angle) => Bullet::new(self.ship_type, self.angle+angle, self.pos, BulletType::Normal, id),
-            _ => Bullet::new(self.ship_type, self.angle, self.pos, bullet_type, id),
-        }
+        let angle = self.angle + curve.unwrap_or(0.0);
+        Bullet::new(self.ship_type, angle, self.pos, bullet_type, id)
     }
 
     pub fn draw(&self, assets: &mut Assets, ctx: &mut Context, version: Option<i32>) -> GameResult {
 
         let img = match self.ship_type {
             Possession::Player => {
-                if self.health < 0.0 {
+                if self.is_dead() {
                     &assets.player_dead
                 }
                 else if self.shield {
@@ -197,11 +221,27 @@ impl Ship {
         graphics::draw(ctx,img,drawparams)
     }
 
+    // regular enemies are half the size of the boss
+    pub fn draw_enemy(&self, kind: &EnemyKind, assets: &mut Assets, ctx: &mut Context) -> GameResult {
+        let img = assets.sprite(ctx, &kind.sprite).cloned().unwrap_or_else(|| assets.enemy_ship.clone());
+
+        let drawparams = graphics::DrawParam::new()
+            .dest(mint::Point2{ x: self.pos.x, y: self.pos.y })
+            .rotation(self.angle)
+            .offset(mint::Point2{ x:0.5, y:0.5 });
+        graphics::draw(ctx,&img,drawparams)
+    }
+
+    // the one place that decides it, a ship at exactly 0 is dead too
+    pub fn is_dead(&self) -> bool {
+        self.health <= 0.0
+    }
+
     // returns true if the ship moved, false otherwise
     pub fn update_pos(&mut self, dt: f32, input_state: &InputState, width: f32, height: f32) -> bool {
         let old_x = self.pos.x;
         let old_y = self.pos.y;
-        if self.health < 0.0 {
+        if self.is_dead() {
             return false
         }
         if input_state.up && self.pos.y >= SCREEN_BORDER {
@@ -219,17 +259,4 @@ impl Ship {
 
         self.pos.x != old_x || self.pos.y != old_y
     }
-
-    // for boss ship
-    pub fn oscillate(&mut self, dt: f32, width: f32) {
-        if self.pos.x <= 0.0 + SCREEN_BORDER {
-            self.direction = Some(1.0);
-        }
-        else if self.pos.x >= width - SCREEN_BORDER {
-            self.direction = Some(-1.0);
-        }
-
-        self.pos.x += dt * BOSS_SPEED * self.direction.unwrap();
-
-    }
 }
@@ -0,0 +1,100 @@
+use crate::constants::*;
+use crate::Point;
+
+use std::collections::{HashMap, VecDeque};
+
+use uuid::Uuid;
+
+//=================
+//    SNAPSHOT
+//=================
+
+// a position and the local time it arrived at. Arrival time is used rather
+// than the sender's clock so peers don't need synchronized clocks.
+#[derive(Copy, Clone, Debug)]
+struct Snapshot {
+    time: f64,
+    pos: Point,
+}
+
+//=================
+//  INTERPOLATOR
+//=================
+
+// Remote entities are drawn `delay` seconds in the past, between the two
+// snapshots around that time, so they move smoothly no matter how bursty
+// the updates are. When updates stop coming the last known velocity is
+// used for a little while, then the entity goes back to where it was last
+// seen: a ship that stopped doesn't send anything more.
+pub struct Interpolator {
+    delay: f64,
+    buffers: HashMap<Uuid, VecDeque<Snapshot>>,
+}
+
+impl Interpolator {
+    pub fn new(delay: f32) -> Interpolator {
+        Interpolator {
+            delay: delay as f64,
+            buffers: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, id: Uuid, time: f64, pos: Point) {
+        let buffer = self.buffers.entry(id).or_default();
+        match buffer.back_mut() {
+            // several updates handled in the same frame, keep the newest
+            Some(last) if last.time >= time => last.pos = pos,
+            _ => buffer.push_back(Snapshot { time, pos }),
+        }
+
+        // keep one snapshot older than the render time to interpolate from
+        let render_time = time - self.delay;
+        while buffer.len() > 2 && buffer[1].time <= render_time {
+            buffer.pop_front();
+        }
+    }
+
+    pub fn remove(&mut self, id: Uuid) {
+        self.buffers.remove(&id);
+    }
+
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+    }
+
+    // where the entity should be drawn at local time `now`
+    pub fn sample(&self, id: Uuid, now: f64) -> Option<Point> {
+        let buffer = self.buffers.get(&id)?;
+        let render_time = now - self.delay;
+
+        let first = buffer.front()?;
+        if render_time <= first.time || buffer.len() == 1 {
+            return Some(first.pos)
+        }
+
+        for pair in buffer.iter().zip(buffer.iter().skip(1)) {
+            let (from, to) = pair;
+            if render_time <= to.time {
+                let t = ((render_time - from.time) / (to.time - from.time)) as f32;
+                return Some(lerp(from.pos, to.pos, t))
+            }
+        }
+
+        // late packets: keep going in the same direction for a bit, then
+        // come back to the last position
+        let last = buffer[buffer.len() - 1];
+        let before = buffer[buffer.len() - 2];
+        let elapsed = (render_time - last.time) as f32;
+        let decay = ((elapsed - MAX_EXTRAPOLATION) / EXTRAPOLATION_DECAY).clamp(0.0, 1.0);
+        let ahead = elapsed.min(MAX_EXTRAPOLATION) * (1.0 - decay);
+        let t = 1.0 + ahead / (last.time - before.time) as f32;
+        Some(lerp(before.pos, last.pos, t))
+    }
+}
+
+fn lerp(from: Point, to: Point, t: f32) -> Point {
+    Point {
+        x: from.x + (to.x - from.x) * t,
+        y: from.y + (to.y - from.y) * t,
+    }
+}
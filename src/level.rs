@@ -0,0 +1,315 @@
+use crate::constants::*;
+use crate::boss::{ self, BossDefinition, BossError, Movement };
+use crate::bullets::{ BulletType, BulletTypes };
+use crate::entities::{ Ship, Bullet };
+use crate::pattern::Timeline;
+use crate::Point;
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256Plus;
+
+use serde::{Serialize, Deserialize};
+
+use uuid::Uuid;
+
+//=================
+//   DEFINITION
+//=================
+
+// The waves fought before the boss, as written in `resources/levels/<name>.ron`.
+// Like the boss, only the host or the server reads the file. The default one
+// has no waves, the match starts with the boss.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LevelDefinition {
+    pub name: String,
+    // file name of the boss waiting after the last wave
+    pub boss: String,
+    pub enemies: Vec<EnemyKind>,
+    // fought in order, a wave is over once its enemies are dead or gone
+    pub waves: Vec<Wave>,
+}
+
+// What every enemy of a kind is.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EnemyKind {
+    // what spawns call it by
+    pub name: String,
+    #[serde(default = "enemy_sprite")]
+    pub sprite: String,
+    pub hitbox: f32,
+    pub health: f32,
+    // pixels per second along its way in
+    pub speed: f32,
+    // its attacks, fired from the moment it appears
+    pub timeline: Timeline,
+}
+
+fn enemy_sprite() -> String {
+    String::from("/enemy_ship.png")
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Wave {
+    // in the order they appear
+    pub spawns: Vec<Spawn>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Spawn {
+    pub enemy: String,
+    // seconds into the wave
+    #[serde(default)]
+    pub at: f32,
+    // the way in, from the first point to the last. An enemy whose path
+    // ends off the screen flies away.
+    pub path: Vec<Point>,
+    // once it's in
+    #[serde(default = "still")]
+    pub movement: Movement,
+}
+
+fn still() -> Movement {
+    Movement::Still
+}
+
+impl LevelDefinition {
+    pub fn parse(source: &str) -> Result<LevelDefinition, LevelError> {
+        ron::from_str(source).map_err(|e| LevelError::Parse(e.to_string()))
+    }
+
+    // The attacks can fire the boss's bullets, so it's checked with it.
+    // Also checked when it comes from the network.
+    pub fn validate(&self, boss: &BossDefinition) -> Result<(), LevelError> {
+        let types = BulletTypes::new(&boss.bullets);
+        for kind in &self.enemies {
+            if !(kind.health > 0.0 && kind.health.is_finite()) {
+                return Err(LevelError::Invalid("an enemy's health has to be positive"))
+            }
+            if !(kind.hitbox > 0.0 && kind.hitbox.is_finite()) {
+                return Err(LevelError::Invalid("an enemy's hitbox has to be positive"))
+            }
+            if !(kind.speed > 0.0 && kind.speed.is_finite()) {
+                return Err(LevelError::Invalid("an enemy's speed has to be positive"))
+            }
+            kind.timeline.validate().map_err(LevelError::Invalid)?;
+            if kind.timeline.cues.iter().any(|cue| types.find(&cue.attack.bullet).is_none()) {
+                return Err(LevelError::Invalid("an attack fires an unknown kind of bullet"))
+            }
+        }
+        for wave in &self.waves {
+            if wave.spawns.is_empty() || wave.spawns.len() > MAX_WAVE_ENEMIES {
+                return Err(LevelError::Limit(format!("a wave has between one and {} enemies", MAX_WAVE_ENEMIES)))
+            }
+            let mut last = 0.0;
+            for spawn in &wave.spawns {
+                if self.kind(&spawn.enemy).is_none() {
+                    return Err(LevelError::Invalid("a spawn names an unknown enemy"))
+                }
+                if !(spawn.at >= last && spawn.at.is_finite()) {
+                    return Err(LevelError::Invalid("the spawns of a wave have to be in order"))
+                }
+                last = spawn.at;
+                if spawn.path.is_empty() {
+                    return Err(LevelError::Invalid("an enemy needs a path to come in"))
+                }
+                spawn.movement.validate().map_err(LevelError::Invalid)?;
+            }
+        }
+        if bincode::serialized_size(self).map_or(true, |size| size > MAX_LEVEL_SIZE) {
+            return Err(LevelError::Invalid("too big to be sent to the other players"))
+        }
+        Ok(())
+    }
+
+    pub fn kind(&self, name: &str) -> Option<usize> {
+        self.enemies.iter().position(|kind| kind.name == name)
+    }
+}
+
+// Reads `levels/<name>.ron` from the resources, and the boss it names.
+pub fn load(resource_dir: &Path, name: &str) -> Result<(LevelDefinition, BossDefinition), LevelError> {
+    let path = resource_dir.join("levels").join(format!("{}.ron", name));
+    let source = fs::read_to_string(&path)
+        .map_err(|e| LevelError::Io(path.display().to_string(), e))?;
+    let level = LevelDefinition::parse(&source)?;
+    let boss = boss::load(resource_dir, &level.boss)?;
+    level.validate(&boss)?;
+    Ok((level, boss))
+}
+
+//=================
+//      WAVES
+//=================
+
+// The wave being fought. The authority decides when it's over, or every
+// peer in lockstep, the others are told with a WaveSignal.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct WaveState {
+    pub wave: usize,
+    // seconds since the wave started
+    pub clock: f32,
+    // how many of its enemies appeared
+    pub spawned: usize,
+    // drawn from the shared rng when the wave starts
+    pub seed: u64,
+}
+
+impl WaveState {
+    pub fn new(wave: usize, seed: u64) -> WaveState {
+        WaveState {
+            wave,
+            clock: 0.0,
+            spawned: 0,
+            seed,
+        }
+    }
+
+    // the enemies that appear after dt more seconds
+    pub fn update(&mut self, level: &LevelDefinition, dt: f32) -> Vec<Enemy> {
+        self.clock += dt;
+        let spawns = &level.waves[self.wave].spawns;
+        let mut enemies = Vec::new();
+        while self.spawned < spawns.len() && spawns[self.spawned].at <= self.clock {
+            enemies.push(Enemy::new(level, self, self.spawned));
+            self.spawned += 1;
+        }
+        enemies
+    }
+
+    pub fn all_spawned(&self, level: &LevelDefinition) -> bool {
+        self.spawned >= level.waves[self.wave].spawns.len()
+    }
+}
+
+//=================
+//     ENEMIES
+//=================
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct Enemy {
+    pub ship: Ship,
+    // in the level's enemies
+    pub kind: usize,
+    // in its wave's spawns
+    pub spawn: usize,
+    // the point it's heading to, on its way in then in its movement
+    pub waypoint: usize,
+    pub entered: bool,
+    // seconds since it appeared
+    pub timeline: f32,
+    // how far into the timeline the attacks were fired
+    pub fired: f32,
+    // every volley gets its own rng from the wave's seed
+    pub volley: u64,
+}
+
+impl Enemy {
+    fn new(level: &LevelDefinition, wave: &WaveState, spawn: usize) -> Enemy {
+        let spawned = &level.waves[wave.wave].spawns[spawn];
+        // validated, the kind is known
+        let kind = level.kind(&spawned.enemy).unwrap_or(0);
+        Enemy {
+            ship: Ship::enemy(&level.enemies[kind], spawned.path[0], enemy_id(wave.wave, spawn)),
+            kind,
+            spawn,
+            waypoint: 0,
+            entered: false,
+            timeline: 0.0,
+            fired: 0.0,
+            volley: (spawn as u64) << 32,
+        }
+    }
+
+    pub fn update(&mut self, level: &LevelDefinition, wave: usize, dt: f32, width: f32) {
+        let spawn = &level.waves[wave].spawns[self.spawn];
+        self.timeline += dt;
+        if self.entered {
+            spawn.movement.apply(&mut self.ship, &mut self.waypoint, dt, width);
+            return
+        }
+
+        let mut step = dt * level.enemies[self.kind].speed;
+        while let Some(&target) = spawn.path.get(self.waypoint) {
+            let distance = crate::distance_2d(self.ship.pos, target);
+            if distance > step {
+                self.ship.pos.x += (target.x - self.ship.pos.x) / distance * step;
+                self.ship.pos.y += (target.y - self.ship.pos.y) / distance * step;
+                return
+            }
+            self.ship.pos = target;
+            step -= distance;
+            self.waypoint += 1;
+        }
+        self.entered = true;
+        self.waypoint = 0;
+    }
+
+    // flew off the screen at the end of its path
+    pub fn gone(&self, width: f32, height: f32) -> bool {
+        let pos = self.ship.pos;
+        self.entered && (pos.x < 0.0 || pos.x > width || pos.y < 0.0 || pos.y > height)
+    }
+
+    pub fn fire(&mut self, level: &LevelDefinition, seed: u64, types: &BulletTypes, targets: &[Point]) -> Vec<Bullet> {
+        let mut bullets = Vec::new();
+        for (attack, fired) in level.enemies[self.kind].timeline.due(self.fired, self.timeline) {
+            let mut rng = Xoshiro256Plus::seed_from_u64(seed.wrapping_add(self.volley));
+            self.volley += 1;
+            let bullet_type = types.find(&attack.bullet).unwrap_or(BulletType::ENEMY);
+            bullets.extend(attack.fire(&self.ship, bullet_type, targets, fired, &mut rng));
+        }
+        self.fired = self.timeline;
+        bullets
+    }
+}
+
+// every peer has to agree on the ids, like the boss's
+pub fn enemy_id(wave: usize, spawn: usize) -> Uuid {
+    Uuid::from_u128((wave as u128 + 1) << 64 | spawn as u128)
+}
+
+// the ids `enemy_id` makes, no player can claim one
+pub fn is_enemy_id(id: Uuid) -> bool {
+    let (wave, spawn) = (id.as_u128() >> 64, id.as_u128() as u64);
+    wave > 0 && wave <= u32::MAX as u128 && spawn < MAX_WAVE_ENEMIES as u64
+}
+
+//=================
+//     ERRORS
+//=================
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io(String, io::Error),
+    Parse(String),
+    Invalid(&'static str),
+    // the reasons that depend on a constant
+    Limit(String),
+    Boss(BossError),
+}
+
+impl From<BossError> for LevelError {
+    fn from(e: BossError) -> LevelError {
+        LevelError::Boss(e)
+    }
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LevelError::Io(path, e) => write!(f, "couldn't read {}: {}", path, e),
+            LevelError::Parse(e) => write!(f, "invalid level: {}", e),
+            LevelError::Invalid(reason) => write!(f, "invalid level: {}", reason),
+            LevelError::Limit(reason) => write!(f, "invalid level: {}", reason),
+            LevelError::Boss(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for LevelError {}
@@ -0,0 +1,53 @@
+pub mod constants;
+pub mod states;
+pub mod entities;
+pub mod assets;
+pub mod network;
+pub mod simulation;
+pub mod server;
+pub mod cli;
+pub mod reliable;
+pub mod protocol;
+pub mod prediction;
+pub mod interpolation;
+pub mod lockstep;
+pub mod checksum;
+pub mod discovery;
+pub mod validation;
+pub mod netsim;
+pub mod transport;
+pub mod snapshot;
+pub mod stats;
+pub mod boss;
+pub mod pattern;
+pub mod bullets;
+pub mod level;
+
+use serde::{Serialize, Deserialize};
+
+use std::env;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+// define own point to encode it
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+// where the images, the font and the bosses are
+pub fn resource_dir() -> PathBuf {
+    if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
+        let mut path = PathBuf::from(manifest_dir);
+        path.push("resources");
+        path
+    }
+    // running from /target/debug
+    else {
+        PathBuf::from("../../resources")
+    }
+}
+
+pub fn distance_2d(p1: Point, p2: Point) -> f32 {
+    (((p1.x-p2.x).powf(2.0)) + ((p1.y-p2.y).powf(2.0))).sqrt()
+}
@@ -0,0 +1,129 @@
+use crate::constants::*;
+use crate::network::Wrapper;
+use crate::simulation::InputState;
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+
+use uuid::Uuid;
+
+//=================
+//    LOCKSTEP
+//=================
+
+// Every peer runs the same fixed frames from the same inputs, so only
+// inputs cross the wire. An input read on frame n is scheduled for frame
+// n + input_delay and a frame only runs once the inputs of every player
+// for it have arrived.
+pub struct Lockstep {
+    frame: u32,
+    input_delay: u32,
+    // sorted so every peer applies the inputs in the same order
+    players: Vec<Uuid>,
+    inputs: BTreeMap<u32, BTreeMap<Uuid, InputState>>,
+    accumulator: f32,
+    running: bool,
+}
+
+impl Lockstep {
+    pub fn new(input_delay: u32) -> Lockstep {
+        Lockstep {
+            frame: 0,
+            input_delay,
+            players: Vec::new(),
+            inputs: BTreeMap::new(),
+            accumulator: 0.0,
+            running: false,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    // Inputs that arrived before the start signal are kept, a faster peer
+    // may already have sent its first ones.
+    pub fn start(&mut self, mut players: Vec<Uuid>) {
+        players.sort();
+        players.dedup();
+
+        // nobody had the chance to send inputs for the first frames
+        for frame in 0..self.input_delay {
+            let inputs = self.inputs.entry(frame).or_default();
+            for &id in &players {
+                inputs.entry(id).or_default();
+            }
+        }
+
+        self.players = players;
+        self.frame = 0;
+        self.accumulator = 0.0;
+        self.running = true;
+    }
+
+    // back to waiting for a start signal
+    pub fn stop(&mut self) {
+        self.frame = 0;
+        self.inputs.clear();
+        self.accumulator = 0.0;
+        self.running = false;
+    }
+
+    // A player that left can't be waited on. The inputs it sent are dropped
+    // too, a peer that got them and one that didn't would run other frames.
+    pub fn remove_player(&mut self, id: Uuid) {
+        self.players.retain(|&player| player != id);
+        for inputs in self.inputs.values_mut() {
+            inputs.remove(&id);
+        }
+    }
+
+    pub fn receive(&mut self, id: Uuid, frame: u32, input: InputState) {
+        // late inputs of a player that left
+        if self.running && !self.players.contains(&id) {
+            return
+        }
+        if frame >= self.frame {
+            self.inputs.entry(frame).or_default().insert(id, input);
+        }
+    }
+
+    // Returns the messages for the other peers and the inputs of every
+    // frame that can be simulated now, oldest first.
+    pub fn advance(&mut self, dt: f32, local_id: Uuid, input: InputState) -> (Vec<Wrapper>, Vec<BTreeMap<Uuid, InputState>>) {
+        let mut outgoing = Vec::new();
+        let mut frames = Vec::new();
+        if !self.running {
+            return (outgoing, frames)
+        }
+
+        self.accumulator = (self.accumulator + dt).min(LOCKSTEP_DT * LOCKSTEP_MAX_CATCHUP as f32);
+
+        while self.accumulator >= LOCKSTEP_DT {
+            // only once per frame, not again on every stalled update
+            let scheduled = self.frame + self.input_delay;
+            if let Entry::Vacant(entry) = self.inputs.entry(scheduled).or_default().entry(local_id) {
+                entry.insert(input);
+                outgoing.push(Wrapper::LockstepInput(local_id, scheduled, input));
+            }
+
+            let ready = self.inputs
+                .get(&self.frame)
+                .is_some_and(|inputs| self.players.iter().all(|id| inputs.contains_key(id)));
+            // waiting on someone
+            if !ready {
+                break
+            }
+
+            frames.push(self.inputs.remove(&self.frame).unwrap_or_default());
+            self.frame += 1;
+            self.accumulator -= LOCKSTEP_DT;
+        }
+
+        (outgoing, frames)
+    }
+}
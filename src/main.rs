@@ -1,47 +1,51 @@
-mod constants;
-mod states;
-mod entities;
-mod assets;
-mod network;
-
-use constants::{ PEER_PORT, HOST_PORT };
-use network::{ Network, Wrapper };
-use states::MainState;
+use orchid::cli::{ self, CliError, Mode };
+use orchid::network::{ Endpoint, Wrapper };
+use orchid::states::MainState;
+use orchid::transport;
+use orchid::boss;
+use orchid::level::{ self, LevelDefinition };
 
 use std::env;
-use std::path;
-use std::net::{UdpSocket, SocketAddrV4};
+use std::io::{Read, Write};
+use std::process;
 
 use rand_xoshiro::rand_core::SeedableRng;
 use rand_xoshiro::Xoshiro256Plus;
 use rand::Rng;
 
-use ggez::{ContextBuilder, GameResult};
+use uuid::Uuid;
+
+use ggez::{ContextBuilder, Context, GameResult};
+use ggez::filesystem;
 use ggez::event;
 use ggez::conf;
 
-use serde::{Serialize, Deserialize};
-
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
-// define own point to encode it
-pub struct Point {
-    x: f32,
-    y: f32,
-}
+fn main() -> GameResult {
+    let mut config = match cli::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(CliError::Help) => {
+            println!("{}", cli::USAGE);
+            process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        },
+    };
 
-fn distance_2d(p1: Point, p2: Point) -> f32 {
-    (((p1.x-p2.x).powf(2.0)) + ((p1.y-p2.y).powf(2.0))).sqrt()
-}
+    let resource_dir = orchid::resource_dir();
 
-fn main() -> GameResult {
-    let resource_dir = if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
-        let mut path = path::PathBuf::from(manifest_dir);
-        path.push("resources");
-        path
-    }
-    // running from /target/debug
-    else {
-        path::PathBuf::from("../../resources")
+    // joining players get the host's
+    let loaded = match &config.boss {
+        Some(name) => boss::load(&resource_dir, name).map(|boss| (LevelDefinition::default(), boss)).map_err(Into::into),
+        None => level::load(&resource_dir, &config.level),
+    };
+    let (level, boss) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        },
     };
 
     let cb = ContextBuilder::new("Orchid", "jfto23")
@@ -49,43 +53,59 @@ fn main() -> GameResult {
         .add_resource_path(resource_dir);
 
     let (ctx, event_loop) = &mut cb.build()?;
- 
 
-    // networking
-    let args: Vec<String> = env::args().collect();
-
-    let (socket, network_type, rng) = match env::args().len() {
-        1 => {
-            let socket = UdpSocket::bind(format!("127.0.0.1:{}",HOST_PORT))?;
-            socket.set_nonblocking(true)?;
-
-            let mut rng_thread = rand::thread_rng();
-            let rng = Xoshiro256Plus::seed_from_u64(rng_thread.gen::<u64>());
-            (socket,Network::Host, Some(rng))
-        }
-        _ => {
-            let socket = UdpSocket::bind(format!("127.0.0.1:{}",PEER_PORT))?;
-            socket.set_nonblocking(true)?;
-
-            let host_addr: SocketAddrV4 = args[1]
-                .clone()
-                .parse()
-                .expect("invalid adress");
-
-            // notify host that a connection occured
-
-            let signal = bincode::serialize(&Wrapper::ConnectSignal).unwrap();
-            socket.send_to(&signal, host_addr)?;
+    if config.player_id.is_none() {
+        config.player_id = Some(player_id(ctx, &config.name));
+    }
 
 
-            (socket,Network::Peer,None)
-        }
+    // networking
+    let transport = transport::bind(config.transport, config.bind_address())?;
+    let mut endpoint = Endpoint::new(transport);
+    endpoint.timeout = config.timeout;
+    endpoint.netsim.conditions = config.conditions;
+
+    let rng = match config.mode {
+        Mode::Host => {
+            let seed = config.seed.unwrap_or_else(|| rand::thread_rng().gen::<u64>());
+            Some(Xoshiro256Plus::seed_from_u64(seed))
+        },
+        Mode::Join(host_addr) => {
+            // notify host that a connection occured
+            endpoint.try_send_to(&Wrapper::ConnectSignal, host_addr)?;
+            None
+        },
+        // we connect once a game is picked
+        Mode::Browse => None,
     };
 
 
-    let mut my_game = MainState::new(ctx, network_type, socket, rng);
+    let mut my_game = MainState::new(ctx, &config, endpoint, rng, boss, level);
 
     event::run(ctx, event_loop, &mut my_game)
 }
 
+// The id of our ship, saved in the user directory so a player who crashed
+// gets their ship back when they join again. One per name, so several
+// players can share a machine.
+fn player_id(ctx: &mut Context, name: &str) -> Uuid {
+    let file_name: String = name.chars().filter(|c| c.is_alphanumeric()).collect();
+    let path = format!("/player-{}.id", file_name);
+
+    let mut saved = String::new();
+    let loaded = filesystem::open(ctx, &path)
+        .ok()
+        .and_then(|mut file| file.read_to_string(&mut saved).ok())
+        .and_then(|_| Uuid::parse_str(saved.trim()).ok());
+    if let Some(id) = loaded {
+        return id
+    }
 
+    let id = Uuid::new_v4();
+    let written = filesystem::create(ctx, &path)
+        .and_then(|mut file| Ok(file.write_all(id.to_string().as_bytes())?));
+    if let Err(e) = written {
+        eprintln!("couldn't save the player id: {}", e);
+    }
+    id
+}
@@ -0,0 +1,128 @@
+use crate::constants::*;
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use rand::Rng;
+
+//=================
+//   CONDITIONS
+//=================
+
+// How bad the simulated network is. Applied to the packets we send and to
+// the ones we receive, so the latency is added in both directions.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Conditions {
+    // seconds
+    pub latency: f32,
+    pub jitter: f32,
+    // chances between 0 and 1
+    pub loss: f32,
+    pub duplicate: f32,
+    pub reorder: f32,
+}
+
+impl Conditions {
+    pub fn is_perfect(&self) -> bool {
+        *self == Conditions::default()
+    }
+}
+
+impl fmt::Display for Conditions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "latency {:.0}ms  jitter {:.0}ms  loss {:.0}%  duplicate {:.0}%  reorder {:.0}%",
+               self.latency * 1000.0, self.jitter * 1000.0,
+               self.loss * 100.0, self.duplicate * 100.0, self.reorder * 100.0)
+    }
+}
+
+//=================
+//   SIMULATOR
+//=================
+
+struct Delayed {
+    due: f32,
+    data: Vec<u8>,
+    addr: SocketAddr,
+}
+
+// Holds datagrams back to fake a bad network on 127.0.0.1. It sits between
+// the endpoint and its transport, the protocol above doesn't know about it.
+#[derive(Default)]
+pub struct Simulator {
+    pub conditions: Conditions,
+    time: f32,
+    outgoing: Vec<Delayed>,
+    incoming: Vec<Delayed>,
+}
+
+impl Simulator {
+    pub fn new(conditions: Conditions) -> Simulator {
+        Simulator {
+            conditions,
+            ..Simulator::default()
+        }
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        self.time += dt;
+    }
+
+    // Returns false when the packet should go out right away, true when
+    // it was held back or lost.
+    pub fn delay_outgoing(&mut self, data: &[u8], addr: SocketAddr) -> bool {
+        schedule(&mut self.outgoing, &self.conditions, self.time, data, addr)
+    }
+
+    pub fn delay_incoming(&mut self, data: &[u8], addr: SocketAddr) -> bool {
+        schedule(&mut self.incoming, &self.conditions, self.time, data, addr)
+    }
+
+    pub fn due_outgoing(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+        take_due(&mut self.outgoing, self.time)
+    }
+
+    pub fn due_incoming(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+        take_due(&mut self.incoming, self.time)
+    }
+}
+
+fn schedule(queue: &mut Vec<Delayed>, conditions: &Conditions, time: f32, data: &[u8], addr: SocketAddr) -> bool {
+    // the queue has to drain first, or turning the simulator off would
+    // reorder packets
+    if conditions.is_perfect() && queue.is_empty() {
+        return false
+    }
+
+    let mut rng = rand::thread_rng();
+    if rng.gen::<f32>() < conditions.loss {
+        return true
+    }
+
+    let copies = if rng.gen::<f32>() < conditions.duplicate { 2 } else { 1 };
+    for _ in 0..copies {
+        let mut delay = conditions.latency;
+        if conditions.jitter > 0.0 {
+            delay += rng.gen_range(-conditions.jitter, conditions.jitter);
+        }
+        // held back long enough for the next packets to overtake it
+        if rng.gen::<f32>() < conditions.reorder {
+            delay += REORDER_DELAY;
+        }
+
+        queue.push(Delayed {
+            due: time + delay.max(0.0),
+            data: data.to_vec(),
+            addr,
+        });
+    }
+    true
+}
+
+fn take_due(queue: &mut Vec<Delayed>, time: f32) -> Vec<(Vec<u8>, SocketAddr)> {
+    queue.sort_by(|a, b| a.due.partial_cmp(&b.due).unwrap());
+    let count = queue.iter().take_while(|delayed| delayed.due <= time).count();
+    queue.drain(..count)
+        .map(|delayed| (delayed.data, delayed.addr))
+        .collect()
+}
@@ -1,6 +1,20 @@
 use crate::entities::Ship;
 use crate::entities::Bullet;
+use crate::prediction::InputCommand;
+use crate::simulation::{ InputState, World, ShipChunk };
+use crate::checksum::Digest;
+use crate::netsim::Simulator;
+use crate::transport::Transport;
+use crate::snapshot::Snapshot;
+use crate::stats::Stats;
+use crate::boss::BossDefinition;
+use crate::level::LevelDefinition;
+use crate::constants::*;
+use crate::reliable::{ Link, Channel, CHANNEL_CONTROL, CHANNEL_GAME };
+use crate::protocol::{ self, Packet, ProtocolError, PROTOCOL_VERSION, MAX_PACKET_SIZE };
 
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::net::SocketAddr;
 
 use rand_xoshiro::Xoshiro256Plus;
@@ -9,7 +23,8 @@ use serde::{Serialize, Deserialize};
 
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Wrapper {
     BulletWrapper(Bullet),
     ShipWrapper(Ship),
@@ -21,15 +36,106 @@ pub enum Wrapper {
     StartSignal,
     RestartSignal,
     WinSignal,
+    // the bullet that hit this ship, so everyone can hide it
     HitSignal(Uuid, Option<u64>),
+    // health of a ship or the boss, decided by the host or the server
+    HealthSignal(Uuid, f32),
+    // this player's ship was shot down
+    KillSignal(Uuid),
+    PlayerInfo(Uuid, String),
+    InputWrapper(Uuid, Vec<InputCommand>),
+    // sent by a dedicated server, it owns player movement from now on
+    AuthoritySignal,
+    // the input of a player for a lockstep frame
+    LockstepInput(Uuid, u32, InputState),
+    // starts a lockstep match with these players and rng
+    LockstepStart(Vec<Uuid>, Option<Xoshiro256Plus>),
+    // a player's lockstep state, to find out when peers diverge
+    StateChecksum(Uuid, Digest),
+    // keeps the link alive when there's nothing else to send
+    Heartbeat,
+    // the player with this ship is quitting
+    DisconnectSignal(Uuid),
+    // a player is, or stops being, ready to start the match
+    ReadySignal(Uuid, bool),
+    // the match so far, for a player joining after the start
+    WorldSnapshot(World),
+    // the ships that didn't fit in it
+    WorldShips(ShipChunk),
+    // positions of the ships, only what changed since the acked snapshot
+    SnapshotWrapper(Snapshot),
+    // None asks for a whole snapshot
+    SnapshotAck(Option<u32>),
+    // the boss the host or the server picked
+    BossWrapper(BossDefinition),
+    // the boss started this phase, decided by the host or the server
+    PhaseSignal(usize),
+    // the waves the host or the server picked, sent after the boss
+    LevelWrapper(LevelDefinition),
+    // this wave of enemies started, decided by the host or the server
+    WaveSignal(usize),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Wrapper {
+    // None means the message is sent unreliably. Only positions and inputs
+    // are sent that way since a newer one is always on its way.
+    pub fn channel(&self) -> Option<Channel> {
+        match self {
+            Wrapper::ShipUpdateWrapper(_) | Wrapper::InputWrapper(..) | Wrapper::Heartbeat => None,
+            Wrapper::SnapshotWrapper(_) | Wrapper::SnapshotAck(_) => None,
+            Wrapper::BulletWrapper(_) | Wrapper::ShipWrapper(_) | Wrapper::HitSignal(..) => Some(CHANNEL_GAME),
+            Wrapper::HealthSignal(..) | Wrapper::KillSignal(_) => Some(CHANNEL_GAME),
+            _ => Some(CHANNEL_CONTROL),
+        }
+    }
+
+    // for the network stats
+    pub fn name(&self) -> &'static str {
+        match self {
+            Wrapper::BulletWrapper(_) => "Bullet",
+            Wrapper::ShipWrapper(_) => "Ship",
+            Wrapper::AddressWrapper(_) => "Address",
+            Wrapper::AddressesWrapper(_) => "Addresses",
+            Wrapper::ShipUpdateWrapper(_) => "ShipUpdate",
+            Wrapper::Rng(_) => "Rng",
+            Wrapper::ConnectSignal => "Connect",
+            Wrapper::StartSignal => "Start",
+            Wrapper::RestartSignal => "Restart",
+            Wrapper::WinSignal => "Win",
+            Wrapper::HitSignal(..) => "Hit",
+            Wrapper::HealthSignal(..) => "Health",
+            Wrapper::KillSignal(_) => "Kill",
+            Wrapper::PlayerInfo(..) => "PlayerInfo",
+            Wrapper::InputWrapper(..) => "Input",
+            Wrapper::AuthoritySignal => "Authority",
+            Wrapper::LockstepInput(..) => "LockstepInput",
+            Wrapper::LockstepStart(..) => "LockstepStart",
+            Wrapper::StateChecksum(..) => "StateChecksum",
+            Wrapper::Heartbeat => "Heartbeat",
+            Wrapper::DisconnectSignal(_) => "Disconnect",
+            Wrapper::ReadySignal(..) => "Ready",
+            Wrapper::WorldSnapshot(_) => "WorldSnapshot",
+            Wrapper::WorldShips(_) => "WorldShips",
+            Wrapper::SnapshotWrapper(_) => "Snapshot",
+            Wrapper::SnapshotAck(_) => "SnapshotAck",
+            Wrapper::BossWrapper(_) => "Boss",
+            Wrapper::PhaseSignal(_) => "Phase",
+            Wrapper::LevelWrapper(_) => "Level",
+            Wrapper::WaveSignal(_) => "Wave",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ShipUpdate {
     pub id: Uuid,
     pub x: f32,
     pub y: f32,
     pub shield: bool,
+    // only sent in snapshots
+    pub angle: Option<f32>,
+    // last input of the ship's owner that the server applied
+    pub ack: Option<u32>,
 }
 
 impl ShipUpdate {
@@ -39,6 +145,8 @@ impl ShipUpdate {
             x,
             y,
             shield,
+            angle: None,
+            ack: None,
         }
     }
 }
@@ -47,3 +155,335 @@ pub enum Network {
     Host,
     Peer,
 }
+
+//=================
+//   ENDPOINT
+//=================
+
+// the transport, the addresses we broadcast to and the reliability state of
+// every link. Used by both the game and the dedicated server so they speak
+// the protocol the same way.
+pub struct Endpoint {
+    transport: Box<dyn Transport>,
+    pub peers: Vec<SocketAddr>,
+    links: HashMap<SocketAddr, Link>,
+    // messages that arrived in order but haven't been handed out yet
+    inbox: VecDeque<(Wrapper, SocketAddr)>,
+    // set when the remote end refused to talk to us
+    pub rejected: Option<String>,
+    // seconds of silence after which a peer is considered gone
+    pub timeout: f32,
+    // seconds since we last heard from every peer
+    last_heard: HashMap<SocketAddr, f32>,
+    // peers the last send to failed, logged once until one goes through
+    unreachable: HashSet<SocketAddr>,
+    heartbeat_timer: f32,
+    // traffic, round trip times and message counts of every peer
+    stats: Stats,
+    // tells our reliable packets apart from the ones of a previous run
+    session: u32,
+    // fakes a bad network between us and the transport, off by default
+    pub netsim: Simulator,
+}
+
+impl Endpoint {
+    pub fn new(transport: Box<dyn Transport>) -> Endpoint {
+        Endpoint {
+            transport,
+            peers: Vec::<SocketAddr>::new(),
+            links: HashMap::new(),
+            inbox: VecDeque::new(),
+            rejected: None,
+            timeout: PEER_TIMEOUT,
+            last_heard: HashMap::new(),
+            unreachable: HashSet::new(),
+            heartbeat_timer: HEARTBEAT_INTERVAL,
+            stats: Stats::new(),
+            session: rand::random(),
+            netsim: Simulator::default(),
+        }
+    }
+
+    // every datagram goes through the network simulator
+    fn transmit(&mut self, data: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.stats.sent(addr, data.len());
+        if !self.netsim.delay_outgoing(data, addr) {
+            self.transport.send(data, addr)?;
+        }
+        Ok(())
+    }
+
+    // A peer we can't send to is the same as one whose packets get lost:
+    // the reliable messages are resent and it times out if it's gone for
+    // good. Nothing is returned, the main loop has to keep going.
+    pub fn send_to(&mut self, msg: &Wrapper, addr: SocketAddr) {
+        match self.try_send_to(msg, addr) {
+            Ok(()) => {
+                self.unreachable.remove(&addr);
+            },
+            Err(e) => {
+                if self.unreachable.insert(addr) {
+                    eprintln!("couldn't send to {}: {}", addr, e);
+                }
+            },
+        }
+    }
+
+    // for the first message to a host, when failing means we can't join
+    pub fn try_send_to(&mut self, msg: &Wrapper, addr: SocketAddr) -> io::Result<()> {
+        self.stats.sent_message(addr, msg);
+        let session = self.session;
+        let encoded = match msg.channel() {
+            Some(channel) => self.links
+                .entry(addr)
+                .or_default()
+                .send(session, channel, msg.clone())?,
+            None => protocol::encode(&Packet::Unreliable(msg.clone()))?,
+        };
+        self.transmit(&encoded, addr)
+    }
+
+    pub fn send_to_peers(&mut self, msg: Wrapper) {
+        for peer in self.peers.clone() {
+            self.send_to(&msg, peer);
+        }
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        self.peers.retain(|&peer| peer != addr);
+        self.links.remove(&addr);
+        self.last_heard.remove(&addr);
+        self.unreachable.remove(&addr);
+        self.stats.remove_peer(addr);
+    }
+
+    // the address the peers reach us at
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_id()
+    }
+
+    pub fn rtt(&self, addr: SocketAddr) -> Option<f32> {
+        self.stats.peer(addr).and_then(|stats| stats.rtt)
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    // relays a message to every peer except the one it came from
+    pub fn send_to_others(&mut self, msg: &Wrapper, src: SocketAddr) {
+        for peer in self.peers.clone() {
+            if peer != src {
+                self.send_to(msg, peer);
+            }
+        }
+    }
+
+    // Resends whatever reliable messages are still waiting for an ack and
+    // lets the peers know we're still here. Returns the peers that were
+    // silent for too long or never acked a message, they are forgotten.
+    pub fn update(&mut self, dt: f32) -> Vec<SocketAddr> {
+        let mut resends = Vec::new();
+        for (&addr, link) in self.links.iter_mut() {
+            resends.extend(link.update(dt).into_iter().map(|data| (data, addr)));
+        }
+        for (data, addr) in resends {
+            self.stats.resent(addr);
+            // a lost resend gets resent again later
+            let _ = self.transmit(&data, addr);
+        }
+        self.stats.update(dt);
+
+        self.netsim.advance(dt);
+        for (data, addr) in self.netsim.due_outgoing() {
+            let _ = self.transport.send(&data, addr);
+        }
+
+        self.heartbeat_timer -= dt;
+        if self.heartbeat_timer < 0.0 {
+            self.send_to_peers(Wrapper::Heartbeat);
+            self.heartbeat_timer = HEARTBEAT_INTERVAL;
+        }
+
+        let mut timed_out = Vec::new();
+        for &peer in &self.peers {
+            let silence = self.last_heard.entry(peer).or_insert(0.0);
+            *silence += dt;
+            let gave_up = self.links.get(&peer).is_some_and(|link| link.gave_up());
+            if *silence > self.timeout || gave_up {
+                timed_out.push(peer);
+            }
+        }
+        // addresses that aren't peers only get their link and stats dropped
+        let stats = &mut self.stats;
+        self.links.retain(|&addr, link| {
+            if link.gave_up() {
+                stats.remove_peer(addr);
+            }
+            !link.gave_up()
+        });
+        for &peer in &timed_out {
+            self.remove_peer(peer);
+        }
+        timed_out
+    }
+
+    // reads every datagram waiting on the transport and returns the messages
+    // that are ready to be handled, in order
+    pub fn poll(&mut self) -> Vec<(Wrapper, SocketAddr)> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        loop {
+            match self.transport.recv(&mut buf) {
+                Ok((amt, src)) => {
+                    if !self.netsim.delay_incoming(&buf[..amt], src) {
+                        self.receive(&buf[..amt], src);
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // an ICMP error left behind by a peer that went away
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(_) => break,
+            }
+        }
+        for (data, src) in self.netsim.due_incoming() {
+            self.receive(&data, src);
+        }
+
+        self.inbox.drain(..).collect()
+    }
+
+    fn receive(&mut self, data: &[u8], src: SocketAddr) {
+        let result = match protocol::decode(data) {
+            Ok(packet) => {
+                if let Some(silence) = self.last_heard.get_mut(&src) {
+                    *silence = 0.0;
+                }
+                let result = self.handle_packet(packet, src);
+                // junk and strangers don't get an entry in the stats
+                if self.peers.contains(&src) || self.links.contains_key(&src) {
+                    self.stats.received(src, data.len());
+                }
+                result
+            },
+            Err(ProtocolError::VersionMismatch(version)) => {
+                eprintln!("rejected {}: {}", src, ProtocolError::VersionMismatch(version));
+                self.stats.dropped_datagram();
+                protocol::encode(&Packet::Reject { version: PROTOCOL_VERSION })
+                    .map_err(io::Error::from)
+                    .and_then(|reject| self.transmit(&reject, src))
+            },
+            Err(_) => {
+                self.stats.dropped_datagram();
+                Ok(())
+            },
+        };
+
+        // failing to answer is the same as the answer getting lost
+        if let Err(e) = result {
+            eprintln!("couldn't reply to {}: {}", src, e);
+        }
+    }
+
+    fn handle_packet(&mut self, packet: Packet, src: SocketAddr) -> io::Result<()> {
+        match packet {
+            // only there to reset the timeout
+            Packet::Unreliable(Wrapper::Heartbeat) => self.stats.received_message(src, &Wrapper::Heartbeat),
+            Packet::Unreliable(msg) => {
+                self.stats.received_message(src, &msg);
+                self.inbox.push_back((msg, src));
+            },
+            Packet::Ack { channel, seq } => {
+                let sample = self.links
+                    .get_mut(&src)
+                    .and_then(|link| link.acknowledge(channel, seq));
+                if let Some(sample) = sample {
+                    self.stats.rtt_sample(src, sample);
+                }
+            },
+            Packet::Reliable { session, channel, seq, msg } => {
+                let link = self.links.entry(src).or_default();
+                if link.check_session(session) {
+                    eprintln!("{} restarted", src);
+                }
+                if let Some(ready) = link.receive(channel, seq, msg) {
+                    for msg in ready {
+                        self.stats.received_message(src, &msg);
+                        self.inbox.push_back((msg, src));
+                    }
+
+                    // duplicates get acked again, the first ack might have been lost
+                    let ack = protocol::encode(&Packet::Ack { channel, seq })?;
+                    return self.transmit(&ack, src)
+                }
+            },
+            Packet::Reject { version } => {
+                let reason = format!("{} refused the connection: {}", src, ProtocolError::VersionMismatch(version));
+                eprintln!("{}", reason);
+                self.rejected = Some(reason);
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netsim::Conditions;
+    use crate::transport::ChannelTransport;
+
+    fn pair() -> (Endpoint, Endpoint, SocketAddr, SocketAddr) {
+        let a: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+        let (a_end, b_end) = ChannelTransport::pair(a, b);
+        (Endpoint::new(Box::new(a_end)), Endpoint::new(Box::new(b_end)), a, b)
+    }
+
+    #[test]
+    fn messages_go_both_ways() {
+        let (mut a, mut b, a_addr, b_addr) = pair();
+        let id = Uuid::new_v4();
+
+        a.send_to(&Wrapper::KillSignal(id), b_addr);
+        let received = b.poll();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(received[0], (Wrapper::KillSignal(killed), src) if killed == id && src == a_addr));
+
+        b.send_to(&Wrapper::DisconnectSignal(id), a_addr);
+        let received = a.poll();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(received[0], (Wrapper::DisconnectSignal(gone), src) if gone == id && src == b_addr));
+    }
+
+    #[test]
+    fn lost_reliable_messages_are_resent_until_acked() {
+        let (mut a, mut b, _, b_addr) = pair();
+
+        // the first try never makes it
+        a.netsim = Simulator::new(Conditions { loss: 1.0, ..Conditions::default() });
+        a.send_to(&Wrapper::StartSignal, b_addr);
+        a.netsim = Simulator::default();
+        assert!(b.poll().is_empty());
+
+        a.update(RELIABLE_RESEND * 1.5);
+        let received = b.poll();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(received[0].0, Wrapper::StartSignal));
+
+        // once the ack is in there's nothing left to resend
+        a.poll();
+        let link = a.links.get_mut(&b_addr).unwrap();
+        assert!(link.update(RELIABLE_RESEND * 1.5).is_empty());
+    }
+
+    #[test]
+    fn junk_leaves_no_stats_behind() {
+        let (mut a, _, _, _) = pair();
+        let stranger: SocketAddr = "127.0.0.1:7003".parse().unwrap();
+
+        a.receive(&[0xde, 0xad, 0xbe, 0xef], stranger);
+        a.update(STATS_WINDOW);
+        assert_eq!(a.stats().dropped(), 1);
+        assert!(a.stats().peer(stranger).is_none());
+    }
+}
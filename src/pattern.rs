@@ -0,0 +1,221 @@
+use crate::constants::*;
+use crate::bullets::{ self, BulletType };
+use crate::entities::{ Ship, Bullet };
+use crate::Point;
+
+use rand::Rng;
+use rand_xoshiro::Xoshiro256Plus;
+
+use serde::{Serialize, Deserialize};
+
+//=================
+//    PATTERNS
+//=================
+
+// The shape of one firing, in degrees around where the attack aims.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Pattern {
+    Single,
+    // one bullet per angle
+    Spread(Vec<f32>),
+    // `count` bullets evenly spread over `width` degrees
+    Fan { count: u32, width: f32 },
+    // `count` bullets evenly spread all around
+    Ring { count: u32 },
+    // one bullet at a random angle between -spread and spread
+    Random { spread: f32 },
+    // a fan swinging `amplitude` degrees to each side and back every `period` firings
+    Wave { count: u32, width: f32, amplitude: f32, period: u32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum Aim {
+    // where the boss faces
+    #[default]
+    Ahead,
+    // the closest player still alive, ahead when there's none
+    Nearest,
+}
+
+// A pattern and what its bullets do. A spiral is a ring or a fan that spins.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attack {
+    pub pattern: Pattern,
+    #[serde(default)]
+    pub aim: Aim,
+    // degrees added to the aim
+    #[serde(default)]
+    pub offset: f32,
+    // degrees the pattern turns every time it's fired
+    #[serde(default)]
+    pub spin: f32,
+    // the name of a bullet kind, the boss's own or a built in one
+    #[serde(default = "enemy_bullet")]
+    pub bullet: String,
+    // seconds the bullets wait before moving
+    #[serde(default)]
+    pub delay: f32,
+}
+
+fn enemy_bullet() -> String {
+    String::from("enemy")
+}
+
+impl Pattern {
+    // fired is how many times the pattern was fired before
+    fn angles(&self, fired: u32, rng: &mut Xoshiro256Plus) -> Vec<f32> {
+        match self {
+            Pattern::Single => vec![0.0],
+            Pattern::Spread(angles) => angles.clone(),
+            Pattern::Fan { count, width } => fan(*count, *width),
+            Pattern::Ring { count } => (0..*count)
+                .map(|i| i as f32 * 360.0 / *count as f32)
+                .collect(),
+            Pattern::Random { spread } => {
+                let spread = spread.abs();
+                vec![if spread > 0.0 { rng.gen_range(-spread, spread) } else { 0.0 }]
+            },
+            Pattern::Wave { count, width, amplitude, period } => {
+                let swing = fired as f32 / *period as f32 * 2.0 * std::f32::consts::PI;
+                fan(*count, *width)
+                    .into_iter()
+                    .map(|angle| angle + amplitude * swing.sin())
+                    .collect()
+            },
+        }
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        let count = match self {
+            Pattern::Single | Pattern::Random { .. } => 1,
+            Pattern::Spread(angles) => angles.len() as u32,
+            Pattern::Fan { count, .. } | Pattern::Ring { count } => *count,
+            Pattern::Wave { count, period, .. } => {
+                if *period == 0 {
+                    return Err("a wave needs a period of at least one firing")
+                }
+                *count
+            },
+        };
+        if count == 0 {
+            return Err("a pattern fires at least one bullet")
+        }
+        if count > MAX_PATTERN_BULLETS {
+            return Err("too many bullets in one pattern")
+        }
+        Ok(())
+    }
+}
+
+fn fan(count: u32, width: f32) -> Vec<f32> {
+    if count < 2 {
+        return vec![0.0]
+    }
+    (0..count)
+        .map(|i| i as f32 * width / (count - 1) as f32 - width / 2.0)
+        .collect()
+}
+
+impl Attack {
+    // Every random value comes from rng, so every peer firing with the same
+    // rng gets the same bullets. Aimed ones can differ a bit outside of
+    // lockstep, the peers don't see the players at the exact same place.
+    pub fn fire(&self, ship: &Ship, bullet_type: BulletType, targets: &[Point], fired: u32, rng: &mut Xoshiro256Plus) -> Vec<Bullet> {
+        let nearest = bullets::closest(ship.pos, targets);
+        let aim = match (self.aim, nearest) {
+            // clockwise from straight up, like the ships
+            (Aim::Nearest, Some(target)) => (target.x - ship.pos.x).atan2(ship.pos.y - target.y),
+            _ => ship.angle,
+        };
+        let center = aim - ship.angle + (self.offset + self.spin * fired as f32).to_radians();
+
+        self.pattern.angles(fired, rng)
+            .iter()
+            .map(|angle| {
+                let mut bullet = ship.shoot(Some(center + angle.to_radians()), bullet_type, Some(rng.gen::<u64>()));
+                bullet.delay = self.delay;
+                bullet
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        self.pattern.validate()?;
+        if !(self.delay >= 0.0 && self.delay.is_finite()) {
+            return Err("a bullet delay can't be negative")
+        }
+        Ok(())
+    }
+}
+
+//=================
+//    TIMELINE
+//=================
+
+// When the attacks of a phase are fired. It starts over once it's done.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Timeline {
+    // seconds
+    pub length: f32,
+    pub cues: Vec<Cue>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Cue {
+    // seconds into the timeline
+    pub at: f32,
+    // fired this many times, `every` seconds apart
+    #[serde(default = "once")]
+    pub times: u32,
+    #[serde(default)]
+    pub every: f32,
+    pub attack: Attack,
+}
+
+fn once() -> u32 {
+    1
+}
+
+impl Timeline {
+    // The attacks due between from (included) and to, in seconds since the
+    // timeline started, in the order they're fired. Each comes with how
+    // many times its cue was fired before.
+    pub fn due(&self, from: f32, to: f32) -> Vec<(&Attack, u32)> {
+        let mut due = Vec::new();
+        let first = (from / self.length).floor() as u32;
+        let last = (to / self.length).floor() as u32;
+        for lap in first..=last {
+            let start = lap as f32 * self.length;
+            for (i, cue) in self.cues.iter().enumerate() {
+                for k in 0..cue.times {
+                    let time = start + cue.at + k as f32 * cue.every;
+                    if time >= from && time < to {
+                        due.push((time, i, &cue.attack, lap * cue.times + k));
+                    }
+                }
+            }
+        }
+        due.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        due.into_iter().map(|(_, _, attack, fired)| (attack, fired)).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(self.length > 0.0 && self.length.is_finite()) {
+            return Err("a timeline's length has to be positive")
+        }
+        for cue in &self.cues {
+            if cue.times == 0 || (cue.times > 1 && !(cue.every > 0.0 && cue.every.is_finite())) {
+                return Err("a cue fires at least once, and at least some time apart")
+            }
+            if cue.times > MAX_CUE_TIMES {
+                return Err("a cue fires too many times")
+            }
+            let end = cue.at + (cue.times - 1) as f32 * cue.every;
+            if !(cue.at >= 0.0 && end < self.length) {
+                return Err("a cue has to fire within its timeline")
+            }
+            cue.attack.validate()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use crate::constants::*;
+use crate::entities::Ship;
+use crate::network::ShipUpdate;
+use crate::simulation::InputState;
+
+use std::collections::VecDeque;
+
+use serde::{Serialize, Deserialize};
+
+//=================
+//  INPUT COMMAND
+//=================
+
+// one frame worth of input, numbered so the server can tell us which ones
+// it already applied to our ship
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct InputCommand {
+    pub seq: u32,
+    pub dt: f32,
+    pub input: InputState,
+}
+
+//=================
+//    PREDICTOR
+//=================
+
+// Used when a dedicated server owns player movement. The local ship keeps
+// moving right away, and every authoritative position is corrected by
+// replaying the inputs the server hasn't seen yet on top of it.
+pub struct Predictor {
+    next_seq: u32,
+    last_ack: Option<u32>,
+    pending: VecDeque<InputCommand>,
+}
+
+impl Default for Predictor {
+    fn default() -> Predictor {
+        Predictor::new()
+    }
+}
+
+impl Predictor {
+    pub fn new() -> Predictor {
+        Predictor {
+            next_seq: 0,
+            last_ack: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // remembers the input that was just applied locally
+    pub fn record(&mut self, dt: f32, input: InputState) {
+        let command = InputCommand {
+            seq: self.next_seq,
+            dt,
+            input,
+        };
+        self.next_seq += 1;
+
+        self.pending.push_back(command);
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+    }
+
+    // The newest unacknowledged inputs. Inputs are sent unreliably, so each
+    // one goes out several times in case the previous packets got lost.
+    pub fn unacknowledged(&self) -> Vec<InputCommand> {
+        let skip = self.pending.len().saturating_sub(INPUT_REDUNDANCY);
+        self.pending.iter().skip(skip).copied().collect()
+    }
+
+    pub fn reconcile(&mut self, ship: &mut Ship, update: &ShipUpdate, width: f32, height: f32) {
+        let ack = match update.ack {
+            Some(ack) => ack,
+            None => return,
+        };
+        // updates can arrive out of order
+        if self.last_ack.is_some_and(|last| ack < last) {
+            return
+        }
+        self.last_ack = Some(ack);
+
+        self.pending.retain(|command| command.seq > ack);
+
+        ship.pos.x = update.x;
+        ship.pos.y = update.y;
+        for command in &self.pending {
+            ship.update_pos(command.dt, &command.input, width, height);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::network::Wrapper;
+use crate::reliable::Channel;
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+//=================
+//     HEADER
+//=================
+
+// Every datagram starts with the same header in every version of the game:
+//
+//   magic (4) | version (2) | kind (1) | payload length (2) | payload
+//
+// so anything that isn't ours gets dropped before bincode ever sees it and
+// an old client can still be told why it's being ignored.
+pub const MAGIC: [u8; 4] = *b"ORCH";
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const HEADER_SIZE: usize = 9;
+// stays under the usual MTU so datagrams don't get fragmented
+pub const MAX_PACKET_SIZE: usize = 1200;
+
+const KIND_UNRELIABLE: u8 = 0;
+const KIND_RELIABLE: u8 = 1;
+const KIND_ACK: u8 = 2;
+const KIND_REJECT: u8 = 3;
+
+//=================
+//     PACKET
+//=================
+
+#[derive(Debug)]
+pub enum Packet {
+    Unreliable(Wrapper),
+    // session is picked at random by every process, so a peer that restarts
+    // on the same address doesn't get mistaken for the old one
+    Reliable { session: u32, channel: Channel, seq: u32, msg: Wrapper },
+    Ack { channel: Channel, seq: u32 },
+    // sent back to a peer whose protocol version doesn't match ours
+    Reject { version: u16 },
+}
+
+//=================
+//     ERRORS
+//=================
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Truncated,
+    BadMagic,
+    VersionMismatch(u16),
+    UnknownKind(u8),
+    Malformed(bincode::Error),
+    // a message that doesn't fit in one datagram, it has to be split
+    TooLarge(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated => write!(f, "truncated packet"),
+            ProtocolError::BadMagic => write!(f, "not an orchid packet"),
+            ProtocolError::VersionMismatch(version) => write!(f,
+                "version mismatch: peer speaks protocol v{}, this build speaks v{}",
+                version, PROTOCOL_VERSION),
+            ProtocolError::UnknownKind(kind) => write!(f, "unknown packet kind {}", kind),
+            ProtocolError::Malformed(e) => write!(f, "malformed payload: {}", e),
+            ProtocolError::TooLarge(size) => write!(f, "packet of {} bytes, at most {} fit", size, MAX_PACKET_SIZE),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<bincode::Error> for ProtocolError {
+    fn from(e: bincode::Error) -> ProtocolError {
+        ProtocolError::Malformed(e)
+    }
+}
+
+// what couldn't be encoded isn't sent, like any other failed send
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+//=================
+//    ENCODING
+//=================
+
+pub fn encode(packet: &Packet) -> Result<Vec<u8>, ProtocolError> {
+    let (kind, payload) = match packet {
+        Packet::Unreliable(msg) => (KIND_UNRELIABLE, bincode::serialize(msg)),
+        Packet::Reliable { session, channel, seq, msg } => (KIND_RELIABLE, bincode::serialize(&(session, channel, seq, msg))),
+        Packet::Ack { channel, seq } => (KIND_ACK, bincode::serialize(&(channel, seq))),
+        Packet::Reject { version } => (KIND_REJECT, bincode::serialize(version)),
+    };
+    let payload = payload?;
+    if HEADER_SIZE + payload.len() > MAX_PACKET_SIZE {
+        return Err(ProtocolError::TooLarge(HEADER_SIZE + payload.len()))
+    }
+
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    data.push(kind);
+    data.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+pub fn decode(data: &[u8]) -> Result<Packet, ProtocolError> {
+    if data.len() < HEADER_SIZE {
+        return Err(ProtocolError::Truncated)
+    }
+    if data[0..4] != MAGIC {
+        return Err(ProtocolError::BadMagic)
+    }
+
+    let version = u16::from_le_bytes(data[4..6].try_into().unwrap());
+    let kind = data[6];
+    let length = u16::from_le_bytes(data[7..9].try_into().unwrap()) as usize;
+
+    // rejects are understood by every version
+    if version != PROTOCOL_VERSION && kind != KIND_REJECT {
+        return Err(ProtocolError::VersionMismatch(version))
+    }
+
+    let payload = &data[HEADER_SIZE..];
+    if payload.len() != length {
+        return Err(ProtocolError::Truncated)
+    }
+
+    let packet = match kind {
+        KIND_UNRELIABLE => Packet::Unreliable(bincode::deserialize(payload)?),
+        KIND_RELIABLE => {
+            let (session, channel, seq, msg) = bincode::deserialize(payload)?;
+            Packet::Reliable { session, channel, seq, msg }
+        },
+        KIND_ACK => {
+            let (channel, seq) = bincode::deserialize(payload)?;
+            Packet::Ack { channel, seq }
+        },
+        KIND_REJECT => Packet::Reject { version: bincode::deserialize(payload)? },
+        _ => return Err(ProtocolError::UnknownKind(kind)),
+    };
+    Ok(packet)
+}
@@ -0,0 +1,145 @@
+use crate::constants::*;
+use crate::network::Wrapper;
+use crate::protocol::{ self, Packet, ProtocolError };
+
+use std::collections::BTreeMap;
+
+//=================
+//    CHANNELS
+//=================
+
+// Reliable messages are ordered per channel, so a lost bullet doesn't hold
+// back a StartSignal and the other way around.
+pub type Channel = u8;
+
+pub const CHANNEL_CONTROL: Channel = 0;
+pub const CHANNEL_GAME: Channel = 1;
+const CHANNEL_COUNT: usize = 2;
+
+//=================
+//      LINK
+//=================
+
+struct Pending {
+    seq: u32,
+    data: Vec<u8>,
+    resend_timer: f32,
+    resends: u32,
+    // seconds since it was first sent
+    age: f32,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    next_send: u32,
+    next_recv: u32,
+    // sent but not acknowledged yet
+    pending: Vec<Pending>,
+    // arrived before the messages that precede them
+    received: BTreeMap<u32, Wrapper>,
+}
+
+// Reliability state for one remote address: sequence numbers, the
+// messages waiting for an ack and the ones waiting for their turn.
+#[derive(Default)]
+pub struct Link {
+    channels: [ChannelState; CHANNEL_COUNT],
+    // session of the process on the other end
+    remote_session: Option<u32>,
+}
+
+impl Link {
+    // Returns the encoded packet, a copy is kept until it gets acknowledged.
+    // A message that can't be encoded doesn't use up a sequence number, the
+    // peer would wait for it forever.
+    pub fn send(&mut self, session: u32, channel: Channel, msg: Wrapper) -> Result<Vec<u8>, ProtocolError> {
+        let state = &mut self.channels[channel as usize];
+        let seq = state.next_send;
+        let data = protocol::encode(&Packet::Reliable { session, channel, seq, msg })?;
+        state.next_send += 1;
+
+        state.pending.push(Pending {
+            seq,
+            data: data.clone(),
+            resend_timer: RELIABLE_RESEND,
+            resends: 0,
+            age: 0.0,
+        });
+        Ok(data)
+    }
+
+    // A new session means the peer restarted on the same address, its
+    // sequence numbers start over and it won't ack our old messages.
+    // Returns true when the link had to be reset.
+    pub fn check_session(&mut self, session: u32) -> bool {
+        let restarted = self.remote_session.is_some_and(|old| old != session);
+        if restarted {
+            *self = Link::default();
+        }
+        self.remote_session = Some(session);
+        restarted
+    }
+
+    // Returns the round trip time of the message. Resent ones don't count,
+    // there's no telling which copy got acknowledged.
+    pub fn acknowledge(&mut self, channel: Channel, seq: u32) -> Option<f32> {
+        let state = self.channels.get_mut(channel as usize)?;
+        let index = state.pending.iter().position(|pending| pending.seq == seq)?;
+        let pending = state.pending.remove(index);
+        if pending.resends == 0 {
+            Some(pending.age)
+        }
+        else {
+            None
+        }
+    }
+
+    // Stores a reliable message and returns every message that can now be
+    // delivered in order. Duplicates are dropped. None means the message
+    // can't be acked: the channel doesn't exist or the message is too far
+    // ahead of the ones we're waiting for, it gets resent.
+    pub fn receive(&mut self, channel: Channel, seq: u32, msg: Wrapper) -> Option<Vec<Wrapper>> {
+        let state = self.channels.get_mut(channel as usize)?;
+
+        if seq >= state.next_recv {
+            if seq - state.next_recv >= RELIABLE_RECEIVE_WINDOW {
+                return None
+            }
+            state.received.insert(seq, msg);
+        }
+
+        let mut ready = Vec::new();
+        while let Some(msg) = state.received.remove(&state.next_recv) {
+            ready.push(msg);
+            state.next_recv += 1;
+        }
+        Some(ready)
+    }
+
+    // Returns the packets that went unacknowledged for too long. Nothing is
+    // ever given up on, the peer would wait for the missing message forever:
+    // see `gave_up`.
+    pub fn update(&mut self, dt: f32) -> Vec<Vec<u8>> {
+        let mut resend = Vec::new();
+        for state in &mut self.channels {
+            for pending in &mut state.pending {
+                pending.age += dt;
+                pending.resend_timer -= dt;
+                if pending.resend_timer < 0.0 {
+                    pending.resend_timer = RELIABLE_RESEND;
+                    pending.resends += 1;
+                    resend.push(pending.data.clone());
+                }
+            }
+        }
+        resend
+    }
+
+    // a message went unacknowledged through every resend, the peer has to
+    // be treated as disconnected
+    pub fn gave_up(&self) -> bool {
+        self.channels
+            .iter()
+            .any(|state| state.pending.iter().any(|pending| pending.resends > RELIABLE_MAX_RESENDS))
+    }
+}
@@ -0,0 +1,358 @@
+use crate::constants::*;
+use crate::network::{ Wrapper, Endpoint };
+use crate::simulation::{ Simulation, State, InputState };
+use crate::prediction::InputCommand;
+use crate::discovery::{ Announcement, Beacon };
+use crate::protocol::PROTOCOL_VERSION;
+use crate::validation::Validator;
+use crate::snapshot::{ Snapshots, ShipState };
+use crate::boss::BossDefinition;
+use crate::level::LevelDefinition;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand_xoshiro::Xoshiro256Plus;
+
+use uuid::Uuid;
+
+//=================
+//     SERVER
+//=================
+
+// Dedicated server. It has no ship of its own: it runs the boss, the bullets
+// and the collisions, moves the players from their inputs and relays
+// whatever else a player sends to everyone else, so the match keeps going
+// no matter which player leaves.
+pub struct Server {
+    sim: Simulation,
+    net: Endpoint,
+    broadcast_timer: f32,
+    names: HashMap<Uuid, String>,
+    // last input applied to every ship the server moves
+    acked_inputs: HashMap<Uuid, u32>,
+    // checks what the players send, and which ship each of them plays
+    validator: Validator,
+    // players ready to start, the match starts once every one of them is
+    ready: HashMap<Uuid, bool>,
+    name: String,
+    beacon: Option<Beacon>,
+    // the positions go out in these
+    snapshots: Snapshots,
+    // seconds between two logs of the network stats, None to never log them
+    pub stats_interval: Option<f32>,
+    stats_timer: f32,
+}
+
+impl Server {
+    pub fn new(net: Endpoint, rng: Xoshiro256Plus, name: String) -> Server {
+        let beacon = Beacon::bind()
+            .map_err(|e| eprintln!("LAN discovery disabled: {}", e))
+            .ok();
+
+        let mut sim = Simulation::new(None, Some(rng));
+        sim.authoritative = true;
+
+        Server {
+            sim,
+            net,
+            broadcast_timer: BROADCAST_TICK,
+            names: HashMap::new(),
+            acked_inputs: HashMap::new(),
+            validator: Validator::new(),
+            ready: HashMap::new(),
+            name,
+            beacon,
+            snapshots: Snapshots::new(),
+            stats_interval: None,
+            stats_timer: 0.0,
+        }
+    }
+
+    pub fn set_boss(&mut self, boss: BossDefinition) {
+        println!("boss: {}", boss.name);
+        self.sim.set_boss(boss);
+    }
+
+    pub fn set_level(&mut self, level: LevelDefinition) {
+        if !level.waves.is_empty() {
+            println!("level: {} ({} waves)", level.name, level.waves.len());
+        }
+        self.sim.set_level(level);
+    }
+
+    // the LAN browser only finds UDP games
+    pub fn disable_discovery(&mut self) {
+        self.beacon = None;
+    }
+
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        let tick = Duration::from_secs_f32(SERVER_TICK);
+        let mut last = Instant::now();
+
+        loop {
+            let start = Instant::now();
+            let dt = start.duration_since(last).as_secs_f32();
+            last = start;
+
+            self.update(dt);
+
+            if let Some(remaining) = tick.checked_sub(start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.broadcast_timer -= dt;
+        self.validator.update(dt);
+        for addr in self.net.update(dt) {
+            println!("{} timed out", addr);
+            self.snapshots.remove_peer(addr);
+            for id in self.validator.owned_by(addr) {
+                self.remove_player(id);
+                self.net.send_to_peers(Wrapper::DisconnectSignal(id));
+            }
+        }
+
+        if let Some(interval) = self.stats_interval {
+            self.stats_timer += dt;
+            if self.stats_timer > interval {
+                println!("{}", self.net.stats());
+                self.stats_timer = 0.0;
+            }
+        }
+
+        // a message we can't handle doesn't stop the match
+        for (msg, src) in self.net.poll() {
+            if let Err(e) = self.handle_message(msg, src) {
+                eprintln!("couldn't handle a message from {}: {}", src, e);
+            }
+        }
+
+        if let (Some(beacon), Ok(addr)) = (&self.beacon, self.net.local_addr()) {
+            beacon.answer(&Announcement {
+                version: PROTOCOL_VERSION,
+                name: self.name.clone(),
+                players: self.sim.other_players.len() as u8,
+                in_progress: !matches!(self.sim.state, State::Loading),
+                port: addr.port(),
+            });
+        }
+
+        // nobody is the host here, the match starts when everyone is ready
+        if let State::Loading = self.sim.state {
+            let players = &self.sim.other_players;
+            if !players.is_empty() && players.iter().all(|ship| self.ready.get(&ship.id) == Some(&true)) {
+                println!("everyone is ready, match started");
+                self.sim.state = State::Playing;
+                self.net.send_to_peers(Wrapper::StartSignal);
+            }
+        }
+
+        for msg in self.sim.step(dt, &InputState::new()) {
+            self.net.send_to_peers(msg);
+        }
+
+        if self.broadcast_timer < 0.0 {
+            // every player needs the same rng to see the same boss bullets
+            if let State::Loading = self.sim.state {
+                self.net.send_to_peers(Wrapper::Rng(self.sim.rng.clone()));
+            }
+            self.broadcast_positions();
+            self.broadcast_timer = BROADCAST_TICK;
+        }
+    }
+
+    // authoritative positions of the boss and the players, with the last
+    // input applied so every player can correct its prediction
+    fn broadcast_positions(&mut self) {
+        let mut ships: Vec<ShipState> = self.sim.other_players
+            .iter()
+            .map(|ship| ShipState::new(ship, self.acked_inputs.get(&ship.id).copied()))
+            .collect();
+        ships.push(ShipState::new(&self.sim.enemy_ship, None));
+        ships.extend(self.sim.enemies.iter().map(|enemy| ShipState::new(&enemy.ship, None)));
+
+        self.snapshots.send(&mut self.net, ships);
+    }
+
+    // the players already in the lobby, for one that just connected
+    fn introduce(&mut self, addr: SocketAddr) {
+        self.net.send_to(&Wrapper::BossWrapper(self.sim.boss.clone()), addr);
+        self.net.send_to(&Wrapper::LevelWrapper(self.sim.level.clone()), addr);
+        let owned = self.validator.owned_by(addr);
+        for ship in self.sim.other_players.clone() {
+            if owned.contains(&ship.id) {
+                continue
+            }
+            self.net.send_to(&Wrapper::ShipWrapper(ship), addr);
+            if let Some(name) = self.names.get(&ship.id).cloned() {
+                self.net.send_to(&Wrapper::PlayerInfo(ship.id, name), addr);
+            }
+            let ready = self.ready.get(&ship.id).copied().unwrap_or(false);
+            self.net.send_to(&Wrapper::ReadySignal(ship.id, ready), addr);
+        }
+    }
+
+    // everything a player joining late needs
+    fn send_world(&mut self, addr: SocketAddr) {
+        println!("{} joined a match in progress", addr);
+        self.net.send_to(&Wrapper::BossWrapper(self.sim.boss.clone()), addr);
+        self.net.send_to(&Wrapper::LevelWrapper(self.sim.level.clone()), addr);
+        let (world, chunks) = self.sim.world();
+        self.net.send_to(&Wrapper::WorldSnapshot(world), addr);
+        for chunk in chunks {
+            self.net.send_to(&Wrapper::WorldShips(chunk), addr);
+        }
+        for bullet in self.sim.bullets.clone() {
+            self.net.send_to(&Wrapper::BulletWrapper(bullet), addr);
+        }
+        for (&id, name) in &self.names.clone() {
+            self.net.send_to(&Wrapper::PlayerInfo(id, name.clone()), addr);
+        }
+    }
+
+    fn remove_player(&mut self, id: Uuid) {
+        self.sim.remove_player(id);
+        self.acked_inputs.remove(&id);
+        self.validator.release(id);
+        self.ready.remove(&id);
+        if let Some(name) = self.names.remove(&id) {
+            println!("'{}' left", name);
+        }
+    }
+
+    fn apply_inputs(&mut self, id: Uuid, commands: &[InputCommand], src: SocketAddr) {
+        for command in commands {
+            let last = self.acked_inputs.get(&id);
+            if last.is_none_or(|&last| command.seq > last) {
+                let dt = command.dt.min(MAX_INPUT_DT);
+                // left unacked, the rest is applied once time caught up
+                if let Err(violation) = self.validator.spend_input(src, dt) {
+                    self.validator.report(src, &violation);
+                    return
+                }
+                self.sim.apply_input(id, dt, &command.input);
+                self.acked_inputs.insert(id, command.seq);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, msg: Wrapper, src: SocketAddr) -> Result<(), Box<dyn Error>> {
+        if let Err(violation) = self.validator.check(&msg, src, &self.sim.other_players) {
+            self.validator.report(src, &violation);
+            return Ok(())
+        }
+
+        match msg {
+            Wrapper::ConnectSignal => {
+                if !self.net.peers.contains(&src) {
+                    println!("{} connected", src);
+                    self.net.peers.push(src);
+                }
+                // a player that restarted numbers its inputs from 0 again
+                for id in self.validator.owned_by(src) {
+                    self.acked_inputs.remove(&id);
+                }
+                // the player only talks to us, we relay to the others
+                let server = self.net.local_addr()?;
+                self.net.send_to(&Wrapper::AddressWrapper(server), src);
+                self.net.send_to(&Wrapper::AuthoritySignal, src);
+                if let State::Playing | State::Won | State::Lost = self.sim.state {
+                    self.send_world(src);
+                }
+                else {
+                    self.introduce(src);
+                }
+                return Ok(())
+            },
+            Wrapper::ShipWrapper(ship) => {
+                // claimed by the validator, maybe by a player coming back
+                // whose inputs are numbered from 0 again
+                if !self.sim.other_players.iter().any(|known| known.id == ship.id) {
+                    self.acked_inputs.remove(&ship.id);
+                }
+                self.sim.add_player(ship);
+            },
+            // the positions go out in our snapshots, nothing to relay
+            Wrapper::ShipUpdateWrapper(ref ship_update) => {
+                // we move this ship ourselves, only the shield is up to the player
+                if self.acked_inputs.contains_key(&ship_update.id) {
+                    if let Some(ship) = self.sim.other_players.iter_mut().find(|ship| ship.id == ship_update.id) {
+                        ship.shield = ship_update.shield;
+                    }
+                    return Ok(())
+                }
+                self.sim.apply_ship_update(ship_update);
+                return Ok(())
+            },
+            Wrapper::SnapshotWrapper(ref snapshot) => {
+                let (ack, updates) = self.snapshots.receive(snapshot, src);
+                if let Some(ack) = ack {
+                    self.net.send_to(&ack, src);
+                }
+                // every ship in it is checked like any other update
+                for update in updates {
+                    self.handle_message(Wrapper::ShipUpdateWrapper(update), src)?;
+                }
+                return Ok(())
+            },
+            Wrapper::SnapshotAck(seq) => {
+                self.snapshots.acknowledge(src, seq);
+                return Ok(())
+            },
+            Wrapper::InputWrapper(id, ref commands) => {
+                self.apply_inputs(id, commands, src);
+                return Ok(())
+            },
+            Wrapper::BulletWrapper(bullet) => self.sim.bullets.push(bullet),
+            // we decide the hits and the damage
+            Wrapper::HitSignal(..) | Wrapper::HealthSignal(..) | Wrapper::KillSignal(_) => return Ok(()),
+            Wrapper::PlayerInfo(id, ref name) => {
+                if let Entry::Vacant(entry) = self.names.entry(id) {
+                    println!("{} is playing as '{}'", src, name);
+                    entry.insert(name.clone());
+                }
+            },
+            Wrapper::ReadySignal(id, ready) => {
+                self.ready.insert(id, ready);
+            },
+            // we start the match ourselves
+            Wrapper::StartSignal => return Ok(()),
+            // only the player that's been here the longest, everyone gets
+            // it back from us, that player included
+            Wrapper::RestartSignal => {
+                if self.net.peers.first() != Some(&src) {
+                    println!("{} isn't allowed to restart the match", src);
+                    return Ok(())
+                }
+                println!("match restarted");
+                self.sim.reset();
+                self.ready.clear();
+                self.net.send_to_peers(Wrapper::RestartSignal);
+                return Ok(())
+            },
+            // the server decides who won, owns the rng and picks the boss and the waves
+            Wrapper::WinSignal | Wrapper::Rng(_) | Wrapper::BossWrapper(_) | Wrapper::PhaseSignal(_) => return Ok(()),
+            Wrapper::LevelWrapper(_) | Wrapper::WaveSignal(_) => return Ok(()),
+            Wrapper::AddressWrapper(_) | Wrapper::AddressesWrapper(_) => return Ok(()),
+            Wrapper::AuthoritySignal | Wrapper::Heartbeat | Wrapper::WorldSnapshot(_) | Wrapper::WorldShips(_) => return Ok(()),
+            Wrapper::DisconnectSignal(id) => {
+                println!("{} disconnected", src);
+                self.remove_player(id);
+                self.net.remove_peer(src);
+                self.snapshots.remove_peer(src);
+            },
+            // lockstep is peer to peer only, we'd fight the players over their ships
+            Wrapper::LockstepInput(..) | Wrapper::LockstepStart(..) | Wrapper::StateChecksum(..) => return Ok(()),
+        }
+
+        self.net.send_to_others(&msg, src);
+        Ok(())
+    }
+}
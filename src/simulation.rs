@@ -0,0 +1,838 @@
+use crate::constants::*;
+use crate::bullets::{ BulletType, BulletTypes };
+use crate::entities::{ Ship, Bullet, Possession };
+use crate::network::{ Wrapper, ShipUpdate };
+use crate::boss::{ BossDefinition, BossState };
+use crate::level::{ LevelDefinition, WaveState, Enemy };
+use crate::{ distance_2d, Point };
+
+use std::collections::BTreeMap;
+
+use rand_xoshiro::Xoshiro256Plus;
+use rand::Rng;
+
+use serde::{Serialize, Deserialize};
+
+use uuid::Uuid;
+
+//=================
+//   GAME STATE
+//=================
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub enum State {
+    Playing,
+    Won,
+    Lost,
+    Loading,
+}
+
+//=================
+//   INPUT STATE
+//=================
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+pub struct InputState {
+    pub up: bool,
+    pub down: bool,
+    pub right: bool,
+    pub left: bool,
+    pub fire: bool,
+    pub special: bool,
+    pub shield: bool,
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        InputState::default()
+    }
+}
+
+//=================
+//   COOLDOWNS
+//=================
+
+// timers limiting how often a ship can shoot, use its special and its shield
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+pub struct Cooldowns {
+    // simulated time in ms at which the ship can shoot again
+    pub fire_delay: f64,
+    pub special: f32,
+    pub shield: f32,
+    pub shield_active: f32,
+}
+
+//=================
+//      WORLD
+//=================
+
+// What a player joining a match in progress needs to catch up. Bullets
+// are sent on their own, there can be too many of them for one packet.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct World {
+    pub state: State,
+    pub time: f64,
+    pub boss: Ship,
+    pub boss_state: Option<BossState>,
+    pub wave: Option<WaveState>,
+    pub enemies: Vec<Enemy>,
+    pub ships: Vec<Ship>,
+    // ships of players that left, kept so they can come back to them
+    pub departed: Vec<Ship>,
+    pub rng: Option<Xoshiro256Plus>,
+}
+
+// Ships that didn't fit in the world, sent right after it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ShipChunk {
+    pub ships: Vec<Ship>,
+    pub departed: Vec<Ship>,
+}
+
+//=================
+//   SIMULATION
+//=================
+
+// Everything needed to advance a match, without any dependency on ggez.
+// Time only moves forward through `step`, so the same simulation can run
+// inside the game window, in a test or on a headless server.
+pub struct Simulation {
+    // None when nobody is playing locally (i.e. a dedicated server)
+    pub player_ship: Option<Ship>,
+    pub enemy_ship: Ship,
+    // what the enemy ship is, the same for every peer
+    pub boss: BossDefinition,
+    // the built in bullets then the boss's
+    pub bullet_types: BulletTypes,
+    // the waves before the boss, the same for every peer
+    pub level: LevelDefinition,
+    // the regular enemies of the current wave
+    pub enemies: Vec<Enemy>,
+    pub bullets: Vec<Bullet>,
+    pub other_players: Vec<Ship>,
+    pub departed: BTreeMap<Uuid, Ship>,
+    pub state: State,
+    pub rng: Option<Xoshiro256Plus>,
+    pub cooldowns: BTreeMap<Uuid, Cooldowns>,
+    pub width: f32,
+    pub height: f32,
+    // every peer computes everything itself, nothing needs to be sent
+    pub lockstep: bool,
+    // decides the hits and the damage for everyone, the host or the server
+    pub authoritative: bool,
+    // simulated time in ms, used instead of the wall clock for fire delays
+    time: f64,
+    // None until the boss's first phase starts
+    boss_state: Option<BossState>,
+    // None before the first wave and once the boss arrived
+    wave: Option<WaveState>,
+    // messages produced during a step that should be sent to the peers
+    outbox: Vec<Wrapper>,
+}
+
+impl Simulation {
+    pub fn new(player_ship: Option<Ship>, rng: Option<Xoshiro256Plus>) -> Simulation {
+        let boss = BossDefinition::default();
+        Simulation {
+            player_ship,
+            enemy_ship: Ship::new(Possession::Enemy),
+            bullet_types: BulletTypes::new(&boss.bullets),
+            boss,
+            level: LevelDefinition::default(),
+            enemies: Vec::new(),
+            bullets: Vec::<Bullet>::new(),
+            other_players: Vec::<Ship>::new(),
+            departed: BTreeMap::new(),
+            state: State::Loading,
+            rng,
+            cooldowns: BTreeMap::new(),
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            lockstep: false,
+            authoritative: false,
+            time: 0.0,
+            boss_state: None,
+            wave: None,
+            outbox: Vec::<Wrapper>::new(),
+        }
+    }
+
+    pub fn resize(&mut self, width: f32, height: f32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    // the boss of the next match, or of this one if it didn't start
+    pub fn set_boss(&mut self, boss: BossDefinition) {
+        self.bullet_types = BulletTypes::new(&boss.bullets);
+        self.boss = boss;
+        if let State::Loading = self.state {
+            self.enemy_ship = Ship::boss(&self.boss);
+            self.boss_state = None;
+        }
+    }
+
+    // the waves of the next match, or of this one if it didn't start
+    pub fn set_level(&mut self, level: LevelDefinition) {
+        self.level = level;
+        if let State::Loading = self.state {
+            self.wave = None;
+            self.enemies.clear();
+        }
+    }
+
+    pub fn player_alive(&self) -> bool {
+        match self.player_ship {
+            Some(ship) => !ship.is_dead(),
+            None => false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(ship) = &mut self.player_ship {
+            ship.reset();
+        }
+        self.enemy_ship = Ship::boss(&self.boss);
+        self.boss_state = None;
+        self.wave = None;
+        self.enemies.clear();
+        self.bullets = Vec::<Bullet>::new();
+        self.cooldowns.clear();
+        self.departed.clear();
+        self.time = 0.0;
+        self.state = State::Loading;
+
+        for ship in &mut self.other_players {
+            ship.reset();
+        }
+    }
+
+    pub fn local_cooldowns(&self) -> Cooldowns {
+        self.player_ship
+            .and_then(|ship| self.cooldowns.get(&ship.id).copied())
+            .unwrap_or_default()
+    }
+
+    pub fn player_ids(&self) -> Vec<Uuid> {
+        self.player_ship
+            .iter()
+            .chain(self.other_players.iter())
+            .map(|ship| ship.id)
+            .collect()
+    }
+
+    // a copy of any player's ship, local or remote
+    fn find_ship(&self, id: Uuid) -> Option<Ship> {
+        self.player_ship
+            .iter()
+            .chain(self.other_players.iter())
+            .find(|ship| ship.id == id)
+            .copied()
+    }
+
+    fn store_ship(&mut self, ship: Ship) {
+        let stored = self.player_ship
+            .iter_mut()
+            .chain(self.other_players.iter_mut())
+            .find(|stored| stored.id == ship.id);
+        if let Some(stored) = stored {
+            *stored = ship;
+        }
+    }
+
+    // adds a remote player's ship unless we already know about it
+    // The authority takes a ship coming back as it left it, whatever the
+    // player says about its health.
+    pub fn add_player(&mut self, ship: Ship) {
+        let index = self.other_players
+            .iter()
+            .position(|&x| x.id == ship.id);
+        if index.is_none() {
+            match self.departed.remove(&ship.id) {
+                Some(known) if self.authoritative => self.other_players.push(known),
+                _ => self.other_players.push(ship),
+            }
+        }
+    }
+
+    pub fn remove_player(&mut self, id: Uuid) {
+        if let Some(i) = self.other_players.iter().position(|ship| ship.id == id) {
+            let ship = self.other_players.remove(i);
+            self.departed.insert(id, ship);
+        }
+        self.cooldowns.remove(&id);
+    }
+
+    // The world and the ships that don't fit with it in one packet, sent
+    // in that order.
+    pub fn world(&self) -> (World, Vec<ShipChunk>) {
+        let mut world = World {
+            // losing is only about our own ship, the match goes on
+            state: match self.state {
+                State::Lost => State::Playing,
+                state => state,
+            },
+            time: self.time,
+            boss: self.enemy_ship,
+            boss_state: self.boss_state,
+            wave: self.wave,
+            enemies: self.enemies.clone(),
+            ships: self.player_ship.iter().chain(self.other_players.iter()).copied().collect(),
+            departed: self.departed.values().copied().collect(),
+            rng: self.rng.clone(),
+        };
+
+        let ships = world.ships.split_off(world.ships.len().min(MAX_WORLD_SHIPS));
+        let room = MAX_WORLD_SHIPS - world.ships.len();
+        let departed = world.departed.split_off(world.departed.len().min(room));
+
+        let mut chunks: Vec<ShipChunk> = ships
+            .chunks(MAX_WORLD_SHIPS)
+            .map(|ships| ShipChunk { ships: ships.to_vec(), departed: Vec::new() })
+            .collect();
+        chunks.extend(departed
+            .chunks(MAX_WORLD_SHIPS)
+            .map(|departed| ShipChunk { ships: Vec::new(), departed: departed.to_vec() }));
+
+        (world, chunks)
+    }
+
+    // Catches up with a match in progress. Our ship is taken back from the
+    // world if we played in it before.
+    pub fn restore(&mut self, world: World) {
+        self.state = world.state;
+        self.time = world.time;
+        self.enemy_ship = world.boss;
+        self.boss_state = world.boss_state;
+        self.wave = world.wave;
+        self.enemies = world.enemies;
+        self.rng = world.rng;
+        self.cooldowns.clear();
+        self.other_players.clear();
+        self.departed.clear();
+        self.restore_ships(ShipChunk {
+            ships: world.ships,
+            departed: world.departed,
+        });
+    }
+
+    // the ships that came with the world or after it
+    pub fn restore_ships(&mut self, chunk: ShipChunk) {
+        let own_id = self.player_ship.map(|ship| ship.id);
+        for ship in chunk.departed {
+            if Some(ship.id) == own_id {
+                self.player_ship = Some(ship);
+            }
+            else {
+                self.departed.insert(ship.id, ship);
+            }
+        }
+        for ship in chunk.ships {
+            if Some(ship.id) == own_id {
+                self.player_ship = Some(ship);
+            }
+            else {
+                self.other_players.push(ship);
+            }
+        }
+    }
+
+    // simulated time in seconds
+    pub fn time(&self) -> f64 {
+        self.time / 1000.0
+    }
+
+    pub fn apply_ship_update(&mut self, ship_update: &ShipUpdate) {
+        if ship_update.id == self.enemy_ship.id {
+            self.enemy_ship.pos.x = ship_update.x;
+            self.enemy_ship.pos.y = ship_update.y;
+            if let Some(angle) = ship_update.angle {
+                self.enemy_ship.angle = angle;
+            }
+            return
+        }
+        if let Some(enemy) = self.enemies.iter_mut().find(|enemy| enemy.ship.id == ship_update.id) {
+            enemy.ship.pos.x = ship_update.x;
+            enemy.ship.pos.y = ship_update.y;
+            return
+        }
+
+        let index = self.other_players
+            .iter()
+            .position(|&x| x.id == ship_update.id);
+        if let Some(i) = index {
+            self.other_players[i].pos.x = ship_update.x;
+            self.other_players[i].pos.y = ship_update.y;
+            self.other_players[i].shield = ship_update.shield;
+            if let Some(angle) = ship_update.angle {
+                self.other_players[i].angle = angle;
+            }
+        }
+    }
+
+    // moves a remote player's ship, used when the server owns movement
+    pub fn apply_input(&mut self, id: Uuid, dt: f32, input_state: &InputState) {
+        let (width, height) = (self.width, self.height);
+        if let Some(ship) = self.other_players.iter_mut().find(|ship| ship.id == id) {
+            ship.update_pos(dt, input_state, width, height);
+        }
+    }
+
+    // Spends a bullet that hit a ship somewhere else, as much as its kind
+    // pierces. The damage of the kind comes with a HealthSignal.
+    pub fn apply_hit(&mut self, ship_id: Uuid, bullet_id: Option<u64>) {
+        let bullet = self.bullets
+            .iter_mut()
+            .find(|bullet| bullet.id == bullet_id);
+        if let Some(bullet) = bullet {
+            // we saw it hit already
+            if bullet.last_hit != Some(ship_id) {
+                bullet.strike(ship_id, self.bullet_types.get(bullet.bullet_type));
+            }
+        }
+    }
+
+    // health decided by the authority, for the boss, an enemy or any player
+    pub fn apply_health(&mut self, id: Uuid, health: f32) {
+        if id == self.enemy_ship.id {
+            self.enemy_ship.health = health;
+            return
+        }
+        if let Some(enemy) = self.enemies.iter_mut().find(|enemy| enemy.ship.id == id) {
+            enemy.ship.health = health;
+            return
+        }
+
+        let ship = self.player_ship
+            .iter_mut()
+            .chain(self.other_players.iter_mut())
+            .find(|ship| ship.id == id);
+        if let Some(ship) = ship {
+            ship.health = health;
+        }
+    }
+
+    // advances the match by dt seconds and returns the messages that
+    // need to be broadcasted because of what happened during the step
+    pub fn step(&mut self, dt: f32, input_state: &InputState) -> Vec<Wrapper> {
+        self.time += dt as f64 * 1000.0;
+
+        if let Some(ship) = &mut self.player_ship {
+            ship.update_pos(dt, input_state, self.width, self.height);
+        }
+
+        self.update_level(dt);
+        self.update_boss(dt);
+
+        self.move_bullets(dt);
+
+        if let Some(mut ship) = self.player_ship {
+            self.control_ship(&mut ship, dt, input_state);
+            self.player_ship = Some(ship);
+        }
+        self.update_enemy();
+        self.check_collisions();
+        self.update_state();
+
+        std::mem::take(&mut self.outbox)
+    }
+
+    // Advances every ship from its owner's inputs. Every peer has to get
+    // the exact same result, so nothing in here may depend on the window
+    // size, the wall clock or the thread rng.
+    pub fn step_lockstep(&mut self, dt: f32, inputs: &BTreeMap<Uuid, InputState>) {
+        self.time += dt as f64 * 1000.0;
+
+        for (&id, input_state) in inputs {
+            if let Some(mut ship) = self.find_ship(id) {
+                ship.update_pos(dt, input_state, self.width, self.height);
+                self.store_ship(ship);
+            }
+        }
+
+        self.update_level(dt);
+        self.update_boss(dt);
+
+        self.move_bullets(dt);
+
+        for (&id, input_state) in inputs {
+            if let Some(mut ship) = self.find_ship(id) {
+                self.control_ship(&mut ship, dt, input_state);
+                self.store_ship(ship);
+            }
+        }
+        self.update_enemy();
+        self.check_collisions();
+        self.update_state();
+
+        // the other peers computed the same thing
+        self.outbox.clear();
+    }
+
+    // homing bullets go after the players alive, or the enemies
+    fn move_bullets(&mut self, dt: f32) {
+        let players: Vec<Point> = self.player_ship
+            .iter()
+            .chain(self.other_players.iter())
+            .filter(|ship| !ship.is_dead())
+            .map(|ship| ship.pos)
+            .collect();
+        let boss = Some(self.enemy_ship).filter(|ship| self.boss_arrived() && !ship.is_dead());
+        let enemies: Vec<Point> = self.enemies
+            .iter()
+            .map(|enemy| enemy.ship)
+            .chain(boss)
+            .map(|ship| ship.pos)
+            .collect();
+
+        for bullet in &mut self.bullets {
+            let targets = match bullet.possession {
+                Possession::Enemy => &players,
+                Possession::Player => &enemies,
+            };
+            bullet.update_pos(self.bullet_types.get(bullet.bullet_type), dt, targets);
+        }
+
+        let (width, height, types) = (self.width, self.height, &self.bullet_types);
+        self.bullets.retain(|bullet| bullet.pos.y > 0.0
+                            && bullet.pos.y < height
+                            && bullet.pos.x > 0.0
+                            && bullet.pos.x < width
+                            && !bullet.hit
+                            && !bullet.expired(types.get(bullet.bullet_type)));
+    }
+
+    fn update_level(&mut self, dt: f32) {
+        if let State::Loading | State::Won = self.state {
+            return
+        }
+        // every peer starts the first wave on its own, with the rng it got
+        // while loading
+        if self.wave.is_none() && self.boss_state.is_none() {
+            if self.level.waves.is_empty() {
+                self.start_boss_phase(0);
+            }
+            else {
+                self.start_wave(0);
+            }
+        }
+
+        let mut cleared = false;
+        if let Some(wave) = &mut self.wave {
+            let spawned = wave.update(&self.level, dt);
+            self.enemies.extend(spawned);
+            for enemy in &mut self.enemies {
+                enemy.update(&self.level, wave.wave, dt, self.width);
+            }
+            let (width, height) = (self.width, self.height);
+            self.enemies.retain(|enemy| !enemy.ship.is_dead() && !enemy.gone(width, height));
+            cleared = wave.all_spawned(&self.level) && self.enemies.is_empty();
+        }
+
+        if cleared && (self.authoritative || self.lockstep) {
+            let next = self.wave.map_or(0, |wave| wave.wave + 1);
+            if next < self.level.waves.len() {
+                self.start_wave(next);
+                self.outbox.push(Wrapper::WaveSignal(next));
+            }
+            else {
+                self.start_boss_phase(0);
+                self.outbox.push(Wrapper::PhaseSignal(0));
+            }
+        }
+    }
+
+    // also when the authority says a wave started, the enemies left from
+    // the previous one were dead already
+    pub fn start_wave(&mut self, wave: usize) {
+        if wave >= self.level.waves.len() {
+            return
+        }
+        let seed = self.draw_seed();
+        self.wave = Some(WaveState::new(wave, seed));
+        self.enemies.clear();
+    }
+
+    pub fn wave(&self) -> Option<&WaveState> {
+        self.wave.as_ref()
+    }
+
+    // the boss is there once the waves are over, right away without any
+    pub fn boss_arrived(&self) -> bool {
+        self.boss_state.is_some() || self.level.waves.is_empty()
+    }
+
+    // one draw per wave or phase, the peers that are told late still draw
+    // the same numbers
+    fn draw_seed(&mut self) -> u64 {
+        match &mut self.rng {
+            Some(rng) => {
+                let seed = rng.gen::<u64>();
+                rng.jump();
+                seed
+            },
+            None => 0,
+        }
+    }
+
+    fn update_boss(&mut self, dt: f32) {
+        if let State::Loading | State::Won = self.state {
+            return
+        }
+
+        if let Some(state) = &mut self.boss_state {
+            state.update(&mut self.enemy_ship, &self.boss, dt, self.width);
+
+            if self.authoritative || self.lockstep {
+                if let Some(next) = self.boss.next_phase(state, self.enemy_ship.health) {
+                    self.start_boss_phase(next);
+                    self.outbox.push(Wrapper::PhaseSignal(next));
+                }
+            }
+        }
+    }
+
+    // also when the authority says a phase started
+    pub fn start_boss_phase(&mut self, phase: usize) {
+        if phase >= self.boss.phases.len() {
+            return
+        }
+        let seed = self.draw_seed();
+        self.boss_state = Some(BossState::new(phase, &self.boss, seed));
+        // the waves are over
+        self.wave = None;
+        self.enemies.clear();
+    }
+
+    pub fn boss_state(&self) -> Option<&BossState> {
+        self.boss_state.as_ref()
+    }
+
+    // The boss dying ends the match for everyone, the players already dead
+    // included: in lockstep every peer has to stop the fight on the same
+    // frame. Losing is only about our own ship.
+    fn update_state(&mut self) {
+        if let State::Playing | State::Lost = self.state {
+            if self.enemy_ship.is_dead() {
+                self.state = State::Won;
+                if self.authoritative {
+                    self.outbox.push(Wrapper::WinSignal);
+                }
+            }
+            else if self.player_ship.is_some_and(|ship| ship.is_dead()) {
+                self.state = State::Lost;
+            }
+        }
+    }
+
+    // shooting, special and shield for one ship
+    fn control_ship(&mut self, ship: &mut Ship, dt: f32, input_state: &InputState) {
+        let mut cooldowns = self.cooldowns.get(&ship.id).copied().unwrap_or_default();
+        cooldowns.special -= dt;
+        cooldowns.shield -= dt;
+
+        // add delay between shots
+        // in lockstep the state is only about the local player, every peer
+        // has to keep spawning the bullets of the ships still alive
+        let can_fire = if self.lockstep {
+            !ship.is_dead() && !matches!(self.state, State::Loading)
+        }
+        else {
+            matches!(self.state, State::Playing | State::Won)
+        };
+
+        if self.time >= cooldowns.fire_delay && input_state.fire {
+            if can_fire {
+                let bullet = ship.shoot(None, BulletType::NORMAL, None);
+                self.bullets.push(bullet);
+                self.outbox.push(Wrapper::BulletWrapper(bullet));
+            }
+
+            cooldowns.fire_delay = self.time + PLAYER_FIRE_RATE;
+        }
+
+        if input_state.special && cooldowns.special < 0.0 {
+            let special_bullet = ship.shoot(None, BulletType::SPECIAL, None);
+            self.bullets.push(special_bullet);
+            self.outbox.push(Wrapper::BulletWrapper(special_bullet));
+
+            cooldowns.special = SPECIAL_BULLET_COOLDOWN;
+        }
+
+        if input_state.shield && cooldowns.shield < 0.0 {
+            ship.shield = true;
+            cooldowns.shield = SHIELD_COOLDOWN;
+            cooldowns.shield_active = SHIELD_DURATION;
+
+            self.outbox.push(Wrapper::ShipUpdateWrapper(ShipUpdate::new(
+                    ship.id,
+                    ship.pos.x,
+                    ship.pos.y,
+                    true)));
+        }
+
+        if cooldowns.shield_active > 0.0 {
+            cooldowns.shield_active -= dt;
+        }
+        else if ship.shield {
+            ship.shield = false;
+            self.outbox.push(Wrapper::ShipUpdateWrapper(ShipUpdate::new(
+                    ship.id,
+                    ship.pos.x,
+                    ship.pos.y,
+                    ship.shield)));
+        }
+
+        self.cooldowns.insert(ship.id, cooldowns);
+    }
+
+    // the boss and the regular enemies fire
+    fn update_enemy(&mut self) {
+        if let State::Loading | State::Won = self.state {
+            return
+        }
+        let targets: Vec<Point> = self.player_ship
+            .iter()
+            .chain(self.other_players.iter())
+            .filter(|ship| !ship.is_dead())
+            .map(|ship| ship.pos)
+            .collect();
+
+        if let Some(wave) = self.wave {
+            for enemy in &mut self.enemies {
+                let bullets = enemy.fire(&self.level, wave.seed, &self.bullet_types, &targets);
+                self.bullets.extend(bullets);
+            }
+        }
+
+        if let Some(state) = &mut self.boss_state {
+            // Until the authority's PhaseSignal reaches a peer it fires the
+            // previous phase's bullets, the hits are decided by the authority
+            // anyway.
+            let bullets = state.fire(&self.enemy_ship, &self.boss, &self.bullet_types, &targets);
+            self.bullets.extend(bullets);
+        }
+    }
+
+    fn check_collisions(&mut self) {
+        // Only the authority changes health, or every peer in lockstep. The
+        // others hide the bullets that hit and wait to hear about the damage.
+        let resolve = self.authoritative || self.lockstep;
+        // bullets still hit a boss in transition, they do no damage
+        let invulnerable = self.boss_state.is_some_and(|state| state.invulnerable());
+        let boss_arrived = self.boss_arrived();
+        let mut damaged = Vec::new();
+
+        for bullet in &mut self.bullets {
+            let kind = self.bullet_types.get(bullet.bullet_type);
+            // a piercing bullet hits the ship it goes through once
+            match bullet.possession {
+                Possession::Enemy => {
+                    let ships = self.player_ship
+                        .iter_mut()
+                        .chain(self.other_players.iter_mut())
+                        .filter(|ship| bullet.last_hit != Some(ship.id));
+
+                    for ship in ships {
+                        let player_distance = distance_2d(bullet.pos, ship.pos);
+                        if player_distance < PLAYER_HITBOX + kind.hitbox && !self.enemy_ship.is_dead() && !ship.is_dead() {
+                            bullet.strike(ship.id, kind);
+                            if resolve {
+                                if !ship.shield {
+                                    ship.health -= kind.damage;
+                                    damaged.push(ship.id);
+                                }
+                                self.outbox.push(Wrapper::HitSignal(ship.id, bullet.id));
+                            }
+                            break
+                        }
+                    }
+                }
+
+                Possession::Player => {
+                    let enemies = self.enemies
+                        .iter_mut()
+                        .filter(|enemy| !enemy.ship.is_dead() && bullet.last_hit != Some(enemy.ship.id));
+
+                    for enemy in enemies {
+                        let hitbox = self.level.enemies[enemy.kind].hitbox;
+                        if distance_2d(bullet.pos, enemy.ship.pos) < hitbox + kind.hitbox {
+                            bullet.strike(enemy.ship.id, kind);
+                            if resolve {
+                                enemy.ship.health -= kind.damage;
+                                damaged.push(enemy.ship.id);
+                            }
+                            break
+                        }
+                    }
+                    if bullet.hit || !boss_arrived {
+                        continue
+                    }
+
+                    let enemy_distance = distance_2d(bullet.pos, self.enemy_ship.pos);
+                    if enemy_distance < self.boss.hitbox + kind.hitbox && bullet.last_hit != Some(self.enemy_ship.id) {
+                        bullet.strike(self.enemy_ship.id, kind);
+                        if resolve && !invulnerable {
+                            self.enemy_ship.health -= kind.damage;
+                            damaged.push(self.enemy_ship.id);
+                        }
+                    }
+                }
+            };
+        }
+
+        // one update per ship, with its health after every hit of the step
+        damaged.sort();
+        damaged.dedup();
+        for id in damaged {
+            if id == self.enemy_ship.id {
+                self.outbox.push(Wrapper::HealthSignal(id, self.enemy_ship.health));
+            }
+            else if let Some(enemy) = self.enemies.iter().find(|enemy| enemy.ship.id == id) {
+                self.outbox.push(Wrapper::HealthSignal(id, enemy.ship.health));
+            }
+            else if let Some(ship) = self.find_ship(id) {
+                self.outbox.push(Wrapper::HealthSignal(id, ship.health));
+                if ship.is_dead() {
+                    self.outbox.push(Wrapper::KillSignal(id));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    #[test]
+    fn ships_move_with_the_input() {
+        let mut sim = Simulation::new(Some(Ship::new(Possession::Player)), None);
+        let start = sim.player_ship.unwrap().pos;
+        let input = InputState { right: true, ..InputState::new() };
+
+        sim.step(DT, &input);
+        let ship = sim.player_ship.unwrap();
+        assert!((ship.pos.x - (start.x + DT * SHIP_SPEED)).abs() < 1e-3);
+        assert_eq!(ship.pos.y, start.y);
+    }
+
+    // the authority takes the damage off and tells everyone about the hit
+    #[test]
+    fn enemy_bullets_hit_the_player() {
+        let mut sim = Simulation::new(Some(Ship::new(Possession::Player)), None);
+        sim.authoritative = true;
+        let ship = sim.player_ship.unwrap();
+        let mut bullet = Bullet::new(Possession::Enemy, 0.0, ship.pos, BulletType::ENEMY, Some(1));
+        bullet.pos = ship.pos;
+        bullet.delay = 1.0;
+        sim.bullets.push(bullet);
+
+        let sent = sim.step(DT, &InputState::new());
+        let damage = sim.bullet_types.get(BulletType::ENEMY).damage;
+        assert_eq!(sim.player_ship.unwrap().health, PLAYER_HEALTH - damage);
+        assert!(sent.iter().any(|msg| matches!(msg, Wrapper::HitSignal(id, Some(1)) if *id == ship.id)));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::constants::*;
+use crate::entities::Ship;
+use crate::network::{ Wrapper, ShipUpdate, Endpoint };
+
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::PI;
+use std::net::SocketAddr;
+
+use serde::{Serialize, Deserialize};
+
+use uuid::Uuid;
+
+//=================
+//   SHIP STATE
+//=================
+
+// A ship as it goes over the network: positions in 1/SNAPSHOT_PRECISION of
+// a pixel and the angle in 1/65536 of a turn.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShipState {
+    pub id: Uuid,
+    x: i16,
+    y: i16,
+    angle: u16,
+    shield: bool,
+    // last input of the ship's owner that the server applied
+    ack: Option<u32>,
+}
+
+impl ShipState {
+    pub fn new(ship: &Ship, ack: Option<u32>) -> ShipState {
+        ShipState {
+            id: ship.id,
+            x: quantize_position(ship.pos.x),
+            y: quantize_position(ship.pos.y),
+            angle: quantize_angle(ship.angle),
+            shield: ship.shield,
+            ack,
+        }
+    }
+
+    pub fn to_update(&self) -> ShipUpdate {
+        let mut update = ShipUpdate::new(
+            self.id,
+            self.x as f32 / SNAPSHOT_PRECISION,
+            self.y as f32 / SNAPSHOT_PRECISION,
+            self.shield);
+        update.angle = Some(self.angle as f32 / 65536.0 * 2.0 * PI);
+        update.ack = self.ack;
+        update
+    }
+}
+
+fn quantize_position(value: f32) -> i16 {
+    (value * SNAPSHOT_PRECISION)
+        .round()
+        .max(i16::MIN as f32)
+        .min(i16::MAX as f32) as i16
+}
+
+fn quantize_angle(angle: f32) -> u16 {
+    let turns = (angle / (2.0 * PI)).rem_euclid(1.0);
+    ((turns * 65536.0).round() as u32 % 65536) as u16
+}
+
+//=================
+//    SNAPSHOT
+//=================
+
+// only the fields that changed since the baseline
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShipDelta {
+    #[serde(with = "compact_id")]
+    pub id: Uuid,
+    x: Option<i16>,
+    y: Option<i16>,
+    angle: Option<u16>,
+    shield: Option<bool>,
+    ack: Option<u32>,
+}
+
+impl ShipDelta {
+    // None when nothing changed
+    fn between(old: Option<&ShipState>, new: &ShipState) -> Option<ShipDelta> {
+        fn changed<T: PartialEq + Copy>(old: Option<T>, new: T) -> Option<T> {
+            if old == Some(new) { None } else { Some(new) }
+        }
+
+        let delta = ShipDelta {
+            id: new.id,
+            x: changed(old.map(|old| old.x), new.x),
+            y: changed(old.map(|old| old.y), new.y),
+            angle: changed(old.map(|old| old.angle), new.angle),
+            shield: changed(old.map(|old| old.shield), new.shield),
+            ack: new.ack.and_then(|ack| changed(old.and_then(|old| old.ack), ack)),
+        };
+        let unchanged = delta.x.is_none() && delta.y.is_none() && delta.angle.is_none()
+            && delta.shield.is_none() && delta.ack.is_none();
+        if old.is_some() && unchanged { None } else { Some(delta) }
+    }
+
+    // a ship the baseline doesn't have comes with every field
+    fn apply(&self, old: Option<&ShipState>) -> Option<ShipState> {
+        Some(ShipState {
+            id: self.id,
+            x: self.x.or(old.map(|old| old.x))?,
+            y: self.y.or(old.map(|old| old.y))?,
+            angle: self.angle.or(old.map(|old| old.angle))?,
+            shield: self.shield.or(old.map(|old| old.shield))?,
+            ack: self.ack.or(old.and_then(|old| old.ack)),
+        })
+    }
+}
+
+// serde gives a Uuid a length, it's always 16 bytes
+mod compact_id {
+    use serde::{Serialize, Deserialize, Serializer, Deserializer};
+    use uuid::Uuid;
+
+    pub fn serialize<S: Serializer>(id: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+        id.as_u128().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+        u128::deserialize(deserializer).map(Uuid::from_u128)
+    }
+}
+
+// The ships one peer sends, as a delta from a snapshot the receiver
+// acknowledged. Without a baseline every ship is sent whole.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub seq: u32,
+    pub baseline: Option<u32>,
+    pub ships: Vec<ShipDelta>,
+    // ships of the baseline that are gone
+    pub removed: Vec<Uuid>,
+}
+
+//=================
+//   SNAPSHOTS
+//=================
+
+// what we last got from a peer
+#[derive(Default)]
+struct Received {
+    latest: Option<u32>,
+    history: VecDeque<(u32, Vec<ShipState>)>,
+}
+
+// Both ends of the snapshots: the ones we send to every peer, delta
+// compressed against what each of them acknowledged, and the ones we get.
+#[derive(Default)]
+pub struct Snapshots {
+    seq: u32,
+    // what we sent lately, the baselines of the next deltas
+    history: VecDeque<(u32, Vec<ShipState>)>,
+    // the newest snapshot every peer acknowledged
+    acked: HashMap<SocketAddr, u32>,
+    received: HashMap<SocketAddr, Received>,
+}
+
+impl Snapshots {
+    pub fn new() -> Snapshots {
+        Snapshots::default()
+    }
+
+    // Sends the ships to every peer, only what changed since the snapshot
+    // it acknowledged. Peers that are up to date get nothing.
+    pub fn send(&mut self, net: &mut Endpoint, ships: Vec<ShipState>) {
+        self.seq += 1;
+        self.history.push_back((self.seq, ships));
+        if self.history.len() > SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+
+        for peer in net.peers.clone() {
+            if let Some(snapshot) = self.encode(peer) {
+                // a lost snapshot is made up for by the next one
+                net.send_to(&Wrapper::SnapshotWrapper(snapshot), peer);
+            }
+        }
+    }
+
+    fn encode(&self, peer: SocketAddr) -> Option<Snapshot> {
+        let (seq, ships) = self.history.back()?;
+        let baseline = self.acked
+            .get(&peer)
+            .and_then(|acked| self.history.iter().find(|(seq, _)| seq == acked));
+
+        let old = |id: Uuid| baseline.and_then(|(_, old)| old.iter().find(|ship| ship.id == id));
+        let deltas: Vec<ShipDelta> = ships
+            .iter()
+            .filter_map(|ship| ShipDelta::between(old(ship.id), ship))
+            .collect();
+        let removed: Vec<Uuid> = baseline
+            .map(|(_, old)| old.iter()
+                .filter(|old| !ships.iter().any(|ship| ship.id == old.id))
+                .map(|old| old.id)
+                .collect())
+            .unwrap_or_default();
+
+        if baseline.is_some() && deltas.is_empty() && removed.is_empty() {
+            return None
+        }
+        Some(Snapshot {
+            seq: *seq,
+            baseline: baseline.map(|(seq, _)| *seq),
+            ships: deltas,
+            removed,
+        })
+    }
+
+    // None means the peer lost its baseline and needs everything again
+    pub fn acknowledge(&mut self, peer: SocketAddr, seq: Option<u32>) {
+        match seq {
+            Some(seq) => {
+                let acked = self.acked.entry(peer).or_insert(seq);
+                *acked = (*acked).max(seq);
+            },
+            None => {
+                self.acked.remove(&peer);
+            },
+        }
+    }
+
+    // Rebuilds the ships of a snapshot from its baseline. Returns the ack
+    // to send back and the ships, which are empty for an old snapshot.
+    pub fn receive(&mut self, snapshot: &Snapshot, src: SocketAddr) -> (Option<Wrapper>, Vec<ShipUpdate>) {
+        let received = self.received.entry(src).or_default();
+
+        // a full snapshot is taken anyway, the peer may have restarted
+        let stale = received.latest.is_some_and(|latest| snapshot.seq <= latest);
+        if stale && snapshot.baseline.is_some() {
+            return (None, Vec::new())
+        }
+
+        let old = match snapshot.baseline {
+            Some(baseline) => match received.history.iter().find(|(seq, _)| *seq == baseline) {
+                Some((_, old)) => old.clone(),
+                // we can't make sense of it, the next one comes whole
+                None => return (Some(Wrapper::SnapshotAck(None)), Vec::new()),
+            },
+            None => Vec::new(),
+        };
+
+        let mut ships: Vec<ShipState> = old
+            .iter()
+            .filter(|old| !snapshot.removed.contains(&old.id))
+            .filter(|old| !snapshot.ships.iter().any(|delta| delta.id == old.id))
+            .copied()
+            .collect();
+        for delta in &snapshot.ships {
+            let old = old.iter().find(|old| old.id == delta.id);
+            match delta.apply(old) {
+                Some(ship) => ships.push(ship),
+                None => return (Some(Wrapper::SnapshotAck(None)), Vec::new()),
+            }
+        }
+
+        if snapshot.baseline.is_none() {
+            received.history.clear();
+        }
+        received.latest = Some(snapshot.seq);
+        received.history.push_back((snapshot.seq, ships.clone()));
+        if received.history.len() > SNAPSHOT_HISTORY {
+            received.history.pop_front();
+        }
+
+        let updates = ships.iter().map(ShipState::to_update).collect();
+        (Some(Wrapper::SnapshotAck(Some(snapshot.seq))), updates)
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        self.acked.remove(&addr);
+        self.received.remove(&addr);
+    }
+}
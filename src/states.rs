@@ -1,174 +1,294 @@
 use crate::constants::*;
-use crate::entities::{ Ship, Bullet, Possession, BulletType } ;
-use crate::network::{ Wrapper, Network, ShipUpdate };
-use crate::distance_2d;
+use crate::entities::{ Ship, Possession };
+use crate::network::{ Wrapper, Network, Endpoint };
+use crate::simulation::{ Simulation, State, InputState };
 use crate::assets::Assets;
-
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::cli::{ Config, Mode };
+use crate::prediction::Predictor;
+use crate::interpolation::Interpolator;
+use crate::lockstep::Lockstep;
+use crate::checksum::DesyncDetector;
+use crate::discovery::{ Announcement, Beacon, Browser };
+use crate::protocol::PROTOCOL_VERSION;
+use crate::transport::TransportKind;
+use crate::snapshot::{ Snapshots, ShipState };
+use crate::boss::BossDefinition;
+use crate::level::LevelDefinition;
+use crate::validation::Validator;
+use crate::Point;
+
+use std::collections::HashMap;
 use std::error::Error;
-use std::net::{UdpSocket, SocketAddr};
-use std::f32::consts;
+use std::net::SocketAddr;
 
 use rand_xoshiro::Xoshiro256Plus;
-use rand::Rng;
+
+use uuid::Uuid;
 
 use ggez::{graphics, Context, GameResult};
 use ggez::event::{EventHandler, KeyCode, KeyMods};
 
-use serde::{Serialize, Deserialize};
-
-//=================
-//   GAME STATE
-//=================
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum State {
-    Playing,
-    Won,
-    Lost,
-    Loading,
-}
-
-//=================
-//   INPUT STATE
-//=================
-
-#[derive(Serialize, Deserialize)]
-pub struct InputState {
-    pub up: bool,
-    pub down: bool,
-    pub right: bool,
-    pub left: bool,
-    pub fire: bool,
-    pub special: bool,
-    pub shield: bool,
-}
-
-impl InputState {
-    fn new() -> InputState {
-        InputState {
-            up: false,
-            down: false,
-            right: false,
-            left: false,
-            fire: false,
-            special: false,
-            shield: false,
-        }
-    }
-}
-
-
 //=================
 //   MAIN STATE
 //=================
 
 pub struct MainState {
-    player_ship: Ship,
-    enemy_ship: Ship,
-    bullets: Vec<Bullet>,
+    sim: Simulation,
     assets: Assets,
     input_state: InputState,
-    player_fire_delay: f64,
-    enemy_fire_delay: f64,
-    special_timer: f32,
-    shield_timer: f32,
-    shield_active: f32,
-    state: State,
-    other_players: Vec::<Ship>,
     network_type: Network,
-    socket: UdpSocket,
-    peers: Vec<SocketAddr>,
+    net: Endpoint,
     broadcast_timer: f32,
-    rng: Option<Xoshiro256Plus>,
+    name: String,
+    // names of the other players, by ship id
+    names: HashMap<Uuid, String>,
+    // only used when a dedicated server owns player movement
+    predictor: Option<Predictor>,
+    // smooths out the positions of the remote ships and the boss
+    interpolator: Interpolator,
+    // only used in lockstep mode
+    lockstep: Option<Lockstep>,
+    desync: DesyncDetector,
+    // the address every remote ship is played from
+    owners: HashMap<Uuid, SocketAddr>,
+    // messages shown for a few seconds, like a player leaving
+    notices: Vec<(String, f32)>,
+    // who is ready to start, by ship id, us included
+    ready: HashMap<Uuid, bool>,
+    // answers the players looking for a game on the LAN, hosts only
+    beacon: Option<Beacon>,
+    // the list of LAN games, until one is picked
+    browser: Option<Browser>,
+    // shows and edits the simulated network conditions
+    netsim_panel: bool,
+    // our ship's position goes out in these
+    snapshots: Snapshots,
+    // shows what the netcode is doing
+    stats_overlay: bool,
+    // the host or the server we joined, the only one that restarts the match
+    host: Option<SocketAddr>,
+    // checks the ships and bullets of the peers when we're the host
+    validator: Validator,
 }
 
 impl MainState {
-    pub fn new(ctx: &mut Context, network_type: Network, socket: UdpSocket, rng: Option<Xoshiro256Plus>) -> MainState {
-        MainState {
-            player_ship: Ship::new(Possession::Player),
-            enemy_ship: Ship::new(Possession::Enemy),
-            bullets: Vec::<Bullet>::new(),
+    pub fn new(ctx: &mut Context, config: &Config, net: Endpoint, rng: Option<Xoshiro256Plus>, boss: BossDefinition, level: LevelDefinition) -> MainState {
+        let mut ship = Ship::new(Possession::Player);
+        if let Some(id) = config.player_id {
+            ship.id = id;
+        }
+        let mut sim = Simulation::new(Some(ship), rng);
+        sim.set_boss(boss);
+        sim.set_level(level);
+        sim.lockstep = config.lockstep;
+        // until a dedicated server says otherwise
+        sim.authoritative = matches!(config.mode, Mode::Host);
+
+        // the LAN browser only finds UDP games
+        let beacon = match config.mode {
+            Mode::Host if config.transport == TransportKind::Udp => Beacon::bind()
+                .map_err(|e| eprintln!("LAN discovery disabled: {}", e))
+                .ok(),
+            _ => None,
+        };
+        let (browser, browse_error) = match config.mode {
+            Mode::Browse => match Browser::new() {
+                Ok(browser) => (Some(browser), None),
+                Err(e) => (None, Some(e)),
+            },
+            _ => (None, None),
+        };
+
+        let mut state = MainState {
+            sim,
             assets: Assets::new(ctx).unwrap(),
             input_state: InputState::new(),
-            player_fire_delay: 0.0,
-            enemy_fire_delay: 0.0,
-            special_timer: 0.0,
-            shield_timer: 0.0,
-            shield_active: 0.0,
-            state: State::Loading,
-            other_players: Vec::<Ship>::new(),
-            network_type: network_type,
-            socket: socket,
-            peers: Vec::<SocketAddr>::new(),
+            network_type: config.network_type(),
+            net,
             broadcast_timer: BROADCAST_TICK,
-            rng: rng,
+            name: config.name.clone(),
+            names: HashMap::new(),
+            predictor: None,
+            interpolator: Interpolator::new(config.interp_delay),
+            lockstep: if config.lockstep { Some(Lockstep::new(config.input_delay)) } else { None },
+            desync: DesyncDetector::new(config.desync_dump.clone()),
+            owners: HashMap::new(),
+            notices: Vec::new(),
+            ready: HashMap::new(),
+            beacon,
+            browser,
+            netsim_panel: false,
+            snapshots: Snapshots::new(),
+            stats_overlay: false,
+            host: match config.mode {
+                Mode::Join(addr) => Some(addr),
+                _ => None,
+            },
+            validator: Validator::new(),
+        };
+        if let Some(e) = browse_error {
+            eprintln!("LAN browser disabled: {}", e);
+            state.notify(format!("can't look for LAN games ({}), use --join", e));
         }
+        state
     }
-fn check_collisions(&mut self) {
 
-        for i in 0..self.bullets.len() {
-            match self.bullets[i].possession {
-                Possession::Enemy => {
-                    let player_distance = distance_2d(self.bullets[i].pos, self.player_ship.pos);
-                    let shield = self.player_ship.shield;
-                    if player_distance < 24.0 && self.enemy_ship.health > 0.0 && self.player_ship.health > 0.0 {
-                        if !shield && self.player_ship.health > 0.0 {
-                            self.player_ship.health -= 2.0;
-                        }
-                        self.bullets[i].hit = true;
-                        let msg = Wrapper::HitSignal(self.player_ship.id, self.bullets[i].id);
-                        self.send_to_peers(msg);
-                    }
-                }
+    fn reset(&mut self) {
+        self.sim.reset();
+        self.input_state = InputState::new();
+        self.interpolator.clear();
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.stop();
+        }
+        self.desync.clear();
+        self.ready.clear();
+    }
 
-                Possession::Player => {
-                    let enemy_distance = distance_2d(self.bullets[i].pos, self.enemy_ship.pos);
-                    if enemy_distance < 40.0 {
-                        match self.bullets[i].bullet_type {
-                            BulletType::Normal => self.enemy_ship.health -= 1.0,
-                            BulletType::Special => self.enemy_ship.health -= SPECIAL_BULLET_DAMAGE,
+    fn notify(&mut self, notice: String) {
+        self.notices.push((notice, NOTICE_DURATION));
+    }
 
-                        }
-                        self.bullets[i].hit = true;
-                    }
+    fn remove_player(&mut self, id: Uuid, reason: &str) {
+        self.sim.remove_player(id);
+        self.interpolator.remove(id);
+        self.owners.remove(&id);
+        self.ready.remove(&id);
+        self.validator.release(id);
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.remove_player(id);
+        }
 
-                }
-            };
+        let name = self.names.remove(&id).unwrap_or_else(|| String::from("a player"));
+        self.notify(format!("{} {}", name, reason));
+    }
+
+    fn announce_kill(&mut self, id: Uuid) {
+        if self.sim.player_ship.is_some_and(|ship| ship.id == id) {
+            self.notify(String::from("you were shot down"));
+        }
+        else {
+            let name = self.names.get(&id).cloned().unwrap_or_else(|| String::from("a player"));
+            self.notify(format!("{} was shot down", name));
+        }
+    }
+
+    // lets the others know right away instead of waiting for the timeout
+    fn disconnect(&mut self) {
+        if let Some(ship) = self.sim.player_ship {
+            self.send_to_peers(Wrapper::DisconnectSignal(ship.id));
         }
     }
 
+    fn lockstep_running(&self) -> bool {
+        self.lockstep.as_ref().is_some_and(Lockstep::running)
+    }
 
-    fn reset(&mut self) {
-            self.player_ship.reset();
-            self.enemy_ship.reset();
-            self.bullets = Vec::<Bullet>::new();
-            self.input_state = InputState::new();
-            self.player_fire_delay = 0.0;
-            self.enemy_fire_delay = 0.0;
-            self.special_timer = 0.0;
-            self.shield_timer = 0.0;
-            self.shield_active = 0.0;
-            self.state = State::Loading;
-
-            for ship in &mut self.other_players {
-                ship.reset();
+    // with a dedicated server nobody is the host, it starts the match itself
+    fn is_host(&self) -> bool {
+        matches!(self.network_type, Network::Host) && self.predictor.is_none()
+    }
+
+    fn is_ready(&self, id: Uuid) -> bool {
+        self.ready.get(&id) == Some(&true)
+    }
+
+    fn everyone_ready(&self) -> bool {
+        self.sim.player_ids().iter().all(|&id| self.is_ready(id))
+    }
+
+    fn toggle_ready(&mut self) {
+        if let Some(ship) = self.sim.player_ship {
+            let ready = !self.is_ready(ship.id);
+            self.ready.insert(ship.id, ready);
+            self.send_to_peers(Wrapper::ReadySignal(ship.id, ready));
+        }
+    }
+
+    fn start(&mut self) {
+        if self.lockstep.is_some() {
+            let players = self.sim.player_ids();
+            let signal = Wrapper::LockstepStart(players.clone(), self.sim.rng.clone());
+            self.send_to_peers(signal);
+            self.start_lockstep(players, self.sim.rng.clone());
+        }
+        else {
+            let signal = Wrapper::StartSignal;
+            self.send_to_peers(signal);
+
+            self.sim.state = State::Playing;
+        }
+    }
+
+    // every peer starts from the same ships, boss and rng
+    fn start_lockstep(&mut self, players: Vec<Uuid>, rng: Option<Xoshiro256Plus>) {
+        let lockstep = match &mut self.lockstep {
+            Some(lockstep) if !lockstep.running() => lockstep,
+            _ => return,
+        };
+
+        let known = self.sim.player_ids();
+        for &id in &players {
+            if !known.contains(&id) {
+                let mut ship = Ship::new(Possession::Player);
+                ship.id = id;
+                self.sim.add_player(ship);
             }
+        }
+
+        self.sim.reset();
+        self.sim.rng = rng;
+        self.sim.resize(SCREEN_WIDTH, SCREEN_HEIGHT);
+        self.sim.state = State::Playing;
+        self.interpolator.clear();
+        lockstep.start(players);
     }
 
+    fn update_lockstep(&mut self, dt: f32) {
+        let (lockstep, ship) = match (&mut self.lockstep, self.sim.player_ship) {
+            (Some(lockstep), Some(ship)) => (lockstep, ship),
+            _ => return,
+        };
+
+        let (outgoing, frames) = lockstep.advance(dt, ship.id, self.input_state);
+        let first = lockstep.frame() - frames.len() as u32;
+        for msg in outgoing {
+            self.net.send_to_peers(msg);
+        }
+        for (i, inputs) in frames.iter().enumerate() {
+            self.sim.step_lockstep(LOCKSTEP_DT, inputs);
+
+            if let Some(checksum) = self.desync.record(&self.sim, first + i as u32 + 1) {
+                self.net.send_to_peers(Wrapper::StateChecksum(ship.id, checksum));
+            }
+        }
+    }
+
+    // a copy of the ship at the position it should be drawn at
+    fn interpolated(&self, ship: &Ship) -> Ship {
+        let mut drawn = *ship;
+        if let State::Loading = self.sim.state {
+            return drawn
+        }
+        if let Some(pos) = self.interpolator.sample(ship.id, self.sim.time()) {
+            drawn.pos = pos;
+        }
+        drawn
+    }
 
     fn draw_indicators(&mut self, ctx: &mut Context) -> GameResult {
 
+        let cooldowns = self.sim.local_cooldowns();
+
         let special_text = graphics::Text::new(("SPECIAL(J)",self.assets.font,16.0));
-        let special_color = if self.special_timer < 0.0 {
+        let special_color = if cooldowns.special < 0.0 {
             GREEN
         }
         else {
             RED
         };
-        
+
         let shield_text = graphics::Text::new(("SHIELD(K)",self.assets.font,16.0));
-        let shield_color = if self.shield_timer < 0.0 {
+        let shield_color = if cooldowns.shield < 0.0 {
             GREEN
         }
         else {
@@ -177,335 +297,563 @@ fn check_collisions(&mut self) {
 
         graphics::draw(ctx, &special_text, (mint::Point2{x:5.0,y:5.0}, 0.0, special_color))?;
         graphics::draw(ctx, &shield_text, (mint::Point2{x:180.0,y:5.0}, 0.0, shield_color))?;
-        Ok(())
-    }
 
-    fn draw_death_screen(&mut self, ctx: &mut Context) -> GameResult {
-        let text = graphics::Text::new(("YOU DIED",self.assets.font,16.0));
-        graphics::draw(ctx, &text, (mint::Point2{x:350.0,y:100.0}, 0.0, graphics::WHITE))?;
+        if let Some(wave) = self.sim.wave() {
+            let label = format!("WAVE {}/{}", wave.wave + 1, self.sim.level.waves.len());
+            let wave_text = graphics::Text::new((label.as_str(),self.assets.font,16.0));
+            let x = 400.0 - wave_text.width(ctx) as f32/2.0;
+            graphics::draw(ctx, &wave_text, (mint::Point2{x,y:5.0}, 0.0, graphics::WHITE))?;
+        }
         Ok(())
     }
 
-    fn draw_win_screen(&mut self, ctx: &mut Context) -> GameResult {
-        let text = graphics::Text::new(("YOU WON",self.assets.font,16.0));
-        graphics::draw(ctx, &text, (mint::Point2{x:350.0,y:100.0}, 0.0, graphics::WHITE))?;
+    fn draw_notices(&mut self, ctx: &mut Context) -> GameResult {
+        for (i, (notice, _)) in self.notices.iter().enumerate() {
+            let text = graphics::Text::new((notice.as_str(),self.assets.font,8.0));
+            graphics::draw(ctx, &text, (mint::Point2{x:5.0,y:30.0 + 15.0*i as f32}, 0.0, graphics::WHITE))?;
+        }
         Ok(())
     }
 
-    fn send_to_peers(&self, msg: Wrapper) {
-        let encoded = bincode::serialize(&msg).unwrap();
-        for peer in self.peers.iter() {
-            self.socket.send_to(&encoded, peer).unwrap();
+    // F1 opens the panel, F2 to F11 lower and raise each condition.
+    // Returns true when the key was used.
+    fn netsim_key(&mut self, key: KeyCode) -> bool {
+        if key == KeyCode::F1 {
+            self.netsim_panel = !self.netsim_panel;
+            return true
         }
-    }
-
-    fn handle_connections(&mut self) -> Result<(), Box<dyn Error>> {
-
-        for ship in &mut self.other_players {
-            ship.move_to_point(PLAYER_SPAWN);
+        if !self.netsim_panel {
+            return false
         }
 
-        // first make all ships appear
-        if self.peers.len() != self.other_players.len() {
-
-            let mut buf = [0u8; 512];
-            let result = self.socket.recv_from(&mut buf);
-
-            match result {
-                Ok(_) => {
-                    let decoded: Wrapper = bincode::deserialize(&buf)?;
-                    match decoded {
-                        Wrapper::ShipWrapper(ship) => {
-                            let index = self.other_players
-                                .iter()
-                                .position(|&x| x.id == ship.id);
-                            match index {
-                                Some(_) => {},
-                                None => self.other_players.push(ship),
-                            }
-                        },
-                        _ => {},
-                    }
-                },
-                Err(_) => {},
-            }
+        let conditions = &mut self.net.netsim.conditions;
+        let (value, step, max) = match key {
+            KeyCode::F2 => (&mut conditions.latency, -NETSIM_DELAY_STEP, 5.0),
+            KeyCode::F3 => (&mut conditions.latency, NETSIM_DELAY_STEP, 5.0),
+            KeyCode::F4 => (&mut conditions.jitter, -NETSIM_DELAY_STEP, 5.0),
+            KeyCode::F5 => (&mut conditions.jitter, NETSIM_DELAY_STEP, 5.0),
+            KeyCode::F6 => (&mut conditions.loss, -NETSIM_CHANCE_STEP, 1.0),
+            KeyCode::F7 => (&mut conditions.loss, NETSIM_CHANCE_STEP, 1.0),
+            KeyCode::F8 => (&mut conditions.duplicate, -NETSIM_CHANCE_STEP, 1.0),
+            KeyCode::F9 => (&mut conditions.duplicate, NETSIM_CHANCE_STEP, 1.0),
+            KeyCode::F10 => (&mut conditions.reorder, -NETSIM_CHANCE_STEP, 1.0),
+            KeyCode::F11 => (&mut conditions.reorder, NETSIM_CHANCE_STEP, 1.0),
+            _ => return false,
+        };
+        // rounded so going back down lands on exactly 0
+        *value = ((*value + step).max(0.0).min(max) * 1000.0).round() / 1000.0;
+        true
+    }
 
+    fn draw_netsim_panel(&mut self, ctx: &mut Context) -> GameResult {
+        if !self.netsim_panel {
+            return Ok(())
         }
 
-        if self.broadcast_timer < 0.0 {
-            let msg  = Wrapper::ShipWrapper(self.player_ship);
-            self.send_to_peers(msg);
-
-            if let Some(_) = self.rng.clone() {
-                let msg = Wrapper::Rng(self.rng.clone());
-                self.send_to_peers(msg);
-            }
-            self.broadcast_timer = BROADCAST_TICK;
+        let conditions = self.net.netsim.conditions;
+        let color = if conditions.is_perfect() { graphics::WHITE } else { RED };
+        let lines = [
+            (String::from("NETWORK SIMULATOR"), graphics::WHITE),
+            (conditions.to_string(), color),
+            (String::from("F2/F3: LATENCY  F4/F5: JITTER  F6/F7: LOSS  F8/F9: DUPLICATE  F10/F11: REORDER"), graphics::WHITE),
+        ];
+        for (i, (line, color)) in lines.iter().enumerate() {
+            let text = graphics::Text::new((line.as_str(),self.assets.font,8.0));
+            graphics::draw(ctx, &text, (mint::Point2{x:5.0,y:545.0 + 15.0*i as f32}, 0.0, *color))?;
         }
+        Ok(())
+    }
 
+    // right aligned in the top corner: every peer, the datagrams we couldn't
+    // read, then the messages received and sent of every kind
+    fn draw_stats(&mut self, ctx: &mut Context) -> GameResult {
+        if !self.stats_overlay {
+            return Ok(())
+        }
 
-        let mut buf = [0u8; 512];
-        let result = self.socket.recv_from(&mut buf);
-
-        match self.network_type {
-            Network::Host => {
-                match result {
-                    // some client connected to host
-                    Ok((_amt, src)) => {
-                        let decoded: Wrapper = bincode::deserialize(&buf)?;
-                        match decoded {
-                            Wrapper::ConnectSignal => {
-                                let new_address = bincode::serialize(&Wrapper::AddressWrapper(src))?;
+        let stats = self.net.stats();
+        let mut lines = Vec::new();
+        for (addr, peer) in stats.peers() {
+            let color = if peer.loss > 0.0 { RED } else { graphics::WHITE };
+            lines.push((addr.to_string(), graphics::WHITE));
+            lines.push((peer.to_string(), color));
+        }
+        let color = if stats.dropped() > 0 { RED } else { graphics::WHITE };
+        lines.push((format!("DROPPED {}", stats.dropped()), color));
+        lines.push((String::from("MESSAGES IN/OUT"), graphics::WHITE));
+        for (name, received, sent) in stats.message_counts() {
+            lines.push((format!("{} {}/{}", name, received, sent), graphics::WHITE));
+        }
 
-                                for peer in self.peers.iter() {
-                                    let encoded_address = bincode::serialize(&Wrapper::AddressWrapper(*peer))?;
-                                    self.socket.send_to(&encoded_address, src)?;
-                                    self.socket.send_to(&new_address, peer)?;
-                                }
-                                let encoded_host = bincode::serialize(&Wrapper::AddressWrapper(self.socket.local_addr()?))?;
-                                self.socket.send_to(&encoded_host, src)?;
-                                self.peers.push(src);
+        for (i, (line, color)) in lines.iter().enumerate() {
+            let text = graphics::Text::new((line.as_str(),self.assets.font,8.0));
+            let x = 795.0 - text.width(ctx) as f32;
+            graphics::draw(ctx, &text, (mint::Point2{x,y:5.0 + 12.0*i as f32}, 0.0, *color))?;
+        }
+        Ok(())
+    }
 
-                            },
+    fn announcement(&self) -> Announcement {
+        Announcement {
+            version: PROTOCOL_VERSION,
+            name: self.name.clone(),
+            players: self.sim.player_ids().len() as u8,
+            in_progress: !matches!(self.sim.state, State::Loading),
+            port: self.net.local_addr().map(|addr| addr.port()).unwrap_or(HOST_PORT),
+        }
+    }
 
-                            Wrapper::StartSignal => self.state = State::Playing,
-                            _ => {},
-                        }
+    fn join_selected(&mut self) {
+        let browser = match &self.browser {
+            Some(browser) => browser,
+            None => return,
+        };
+        let (addr, version) = match browser.selected() {
+            Some(game) => (game.addr, game.announcement.version),
+            None => return,
+        };
 
-                    },
+        if version != PROTOCOL_VERSION {
+            self.notify(format!("{} speaks protocol v{}, this build speaks v{}", addr, version, PROTOCOL_VERSION));
+            return
+        }
+        if let Err(e) = self.net.try_send_to(&Wrapper::ConnectSignal, addr) {
+            self.notify(format!("couldn't reach {}: {}", addr, e));
+            return
+        }
+        self.host = Some(addr);
+        self.browser = None;
+    }
 
-                    Err(_) => {},
-                }
-            },
+    fn draw_browser(&mut self, ctx: &mut Context) -> GameResult {
+        let browser = match &self.browser {
+            Some(browser) => browser,
+            None => return Ok(()),
+        };
 
-            Network::Peer => {
-                match result {
-                    Ok((_amt, _src)) => {
-                        let decoded: Wrapper = bincode::deserialize(&buf)?;
-                        match decoded {
-                            Wrapper::AddressWrapper(address) => {
-                                self.peers.push(address);
-                            },
-                            Wrapper::StartSignal => self.state = State::Playing,
-                            Wrapper::Rng(rng) => self.rng = rng,
-                            _ => {}
-                        }
-                    },
-                    Err(_) => {},
+        let title = graphics::Text::new(("LAN GAMES",self.assets.font,16.0));
+        graphics::draw(ctx, &title, (mint::Point2{x:330.0,y:100.0}, 0.0, graphics::WHITE))?;
 
-                }
+        if browser.games().is_empty() {
+            let text = graphics::Text::new(("SEARCHING...",self.assets.font,8.0));
+            graphics::draw(ctx, &text, (mint::Point2{x:350.0,y:150.0}, 0.0, graphics::WHITE))?;
+        }
 
+        for (i, game) in browser.games().iter().enumerate() {
+            let info = &game.announcement;
+            let status = if info.version != PROTOCOL_VERSION {
+                format!("v{}", info.version)
             }
+            else if info.in_progress {
+                String::from("IN PROGRESS")
+            }
+            else {
+                String::from("LOBBY")
+            };
+            let cursor = if i == browser.selected_index() { ">" } else { " " };
+            let row = format!("{} {:<16} {} players   {:<11} {}", cursor, info.name, info.players, status, game.addr);
+            let color = if info.version == PROTOCOL_VERSION { graphics::WHITE } else { RED };
+
+            let text = graphics::Text::new((row.as_str(),self.assets.font,8.0));
+            graphics::draw(ctx, &text, (mint::Point2{x:120.0,y:150.0 + 20.0*i as f32}, 0.0, color))?;
         }
 
+        let hint = graphics::Text::new(("UP/DOWN: SELECT    ENTER: JOIN    Q: QUIT",self.assets.font,8.0));
+        let x = 400.0 - hint.width(ctx) as f32/2.0;
+        graphics::draw(ctx, &hint, (mint::Point2{x,y:450.0}, 0.0, graphics::WHITE))?;
         Ok(())
     }
 
-    fn handle_updates(&mut self) -> Result<(), Box<dyn Error>> {
-
-        let mut buf = [0u8; 128];
-        let result = self.socket.recv_from(&mut buf);
-        match result {
-            Ok((_amt, _src)) => {
-                let decoded: Wrapper = bincode::deserialize(&buf)?;
-
-                match decoded {
-                    Wrapper::ShipUpdateWrapper(ship_update) => {
-                        let index = self.other_players
-                            .iter()
-                            .position(|&x| x.id == ship_update.id);
-                        match index {
-                            Some(i) => {
-                                self.other_players[i].pos.x = ship_update.x;
-                                self.other_players[i].pos.y = ship_update.y;
-                                self.other_players[i].shield = ship_update.shield;
-                            },
-                            None => {},
-                        }
-                    },
-                    Wrapper::BulletWrapper(bullet) => {
-                        self.bullets.push(bullet);
-                    },
-                    Wrapper::RestartSignal => self.reset(),
-                    Wrapper::WinSignal => self.state = State::Won,
-                    Wrapper::HitSignal(ship_id, bullet_id) => {
-                        let index = self.other_players
-                            .iter()
-                            .position(|&x| x.id == ship_id);
-                        if let Some(i) = index { 
-                            if !self.other_players[i].shield { 
-                                self.other_players[i].health -= 2.0;
-                            }
+    fn draw_lobby(&mut self, ctx: &mut Context) -> GameResult {
+        let title = graphics::Text::new(("LOBBY",self.assets.font,16.0));
+        graphics::draw(ctx, &title, (mint::Point2{x:360.0,y:150.0}, 0.0, graphics::WHITE))?;
 
-                        }
-                        let index = self.bullets
-                            .iter()
-                            .position(|&x| x.id == bullet_id);
-                        if let Some(i) = index { 
-                            self.bullets[i].hit = true;
-                        }
-                    }
-                    _ => {}
-                }
-
-            },
-
-            Err(_) => {},
+        let own_id = self.sim.player_ship.map(|ship| ship.id);
+        let mut rows = Vec::new();
+        for id in self.sim.player_ids() {
+            let name = if Some(id) == own_id {
+                self.name.clone()
+            }
+            else {
+                self.names.get(&id).cloned().unwrap_or_else(|| String::from("..."))
+            };
+            let ping = match self.owners.get(&id).and_then(|&addr| self.net.rtt(addr)) {
+                Some(rtt) => format!("{:.0} ms", rtt * 1000.0),
+                None => String::from("-"),
+            };
+            let (status, color) = if self.is_ready(id) { ("READY", GREEN) } else { ("NOT READY", RED) };
+            rows.push((format!("{:<16} {:>7}   {}", name, ping, status), color));
+        }
 
+        for (i, (row, color)) in rows.iter().enumerate() {
+            let text = graphics::Text::new((row.as_str(),self.assets.font,8.0));
+            graphics::draw(ctx, &text, (mint::Point2{x:220.0,y:190.0 + 20.0*i as f32}, 0.0, *color))?;
         }
 
+        let hint = if !self.is_host() && self.predictor.is_some() {
+            "ENTER: READY    THE MATCH STARTS WHEN EVERYONE IS READY"
+        }
+        else if !self.is_host() {
+            "ENTER: READY    WAITING FOR THE HOST TO START"
+        }
+        else if self.everyone_ready() {
+            "ENTER: READY    SPACE: START"
+        }
+        else {
+            "ENTER: READY    WAITING FOR EVERYONE TO BE READY"
+        };
+        let text = graphics::Text::new((hint,self.assets.font,8.0));
+        let x = 400.0 - text.width(ctx) as f32/2.0;
+        graphics::draw(ctx, &text, (mint::Point2{x,y:450.0}, 0.0, graphics::WHITE))?;
         Ok(())
     }
-}
 
-impl EventHandler for MainState {
-    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
-
-        let dt = ggez::timer::duration_to_f64(ggez::timer::delta(ctx)) as f32;
-
-        self.broadcast_timer -= dt;
+    fn draw_death_screen(&mut self, ctx: &mut Context) -> GameResult {
+        let text = graphics::Text::new(("YOU DIED",self.assets.font,16.0));
+        graphics::draw(ctx, &text, (mint::Point2{x:350.0,y:100.0}, 0.0, graphics::WHITE))?;
+        Ok(())
+    }
 
-        let (width, height) = graphics::drawable_size(ctx);
+    fn draw_win_screen(&mut self, ctx: &mut Context) -> GameResult {
+        let text = graphics::Text::new(("YOU WON",self.assets.font,16.0));
+        graphics::draw(ctx, &text, (mint::Point2{x:350.0,y:100.0}, 0.0, graphics::WHITE))?;
+        Ok(())
+    }
 
-        let moved = self.player_ship.update_pos(dt, &self.input_state, width, height);
+    fn draw_rejection(&mut self, ctx: &mut Context, reason: &str) -> GameResult {
+        let title = graphics::Text::new(("CONNECTION REFUSED",self.assets.font,16.0));
+        let text = graphics::Text::new((reason,self.assets.font,8.0));
+        graphics::draw(ctx, &title, (mint::Point2{x:260.0,y:260.0}, 0.0, RED))?;
+        graphics::draw(ctx, &text, (mint::Point2{x:20.0,y:300.0}, 0.0, graphics::WHITE))?;
+        Ok(())
+    }
 
-        // broadcast_timer limits the amount of time the position of the ship gets broadcasted 
-        // to all peers. 
-        if moved && self.broadcast_timer < 0.0 {
-            let movement = Wrapper::ShipUpdateWrapper(ShipUpdate::new(
-                    self.player_ship.id,
-                    self.player_ship.pos.x,
-                    self.player_ship.pos.y,
-                    self.player_ship.shield));
+    fn send_to_peers(&mut self, msg: Wrapper) {
+        self.net.send_to_peers(msg);
+    }
 
-            self.send_to_peers(movement);
-            self.broadcast_timer = BROADCAST_TICK;
+    fn handle_connections(&mut self) {
+        for ship in &mut self.sim.other_players {
+            ship.move_to_point(PLAYER_SPAWN);
         }
+    }
 
-        if let State::Playing | State::Lost = self.state {
-            self.enemy_ship.oscillate(dt, width);
+    // Everything a new peer needs to know about us. Sent once, reliably,
+    // the position follows in the snapshots.
+    fn introduce(&mut self, addr: SocketAddr) {
+        if let Some(ship) = self.sim.player_ship {
+            self.net.send_to(&Wrapper::ShipWrapper(ship), addr);
+            self.net.send_to(&Wrapper::PlayerInfo(ship.id, self.name.clone()), addr);
+            self.net.send_to(&Wrapper::ReadySignal(ship.id, self.is_ready(ship.id)), addr);
         }
-
-        for bullet in &mut self.bullets {
-            bullet.update_pos(dt);
+        if let (Network::Host, Some(_)) = (&self.network_type, &self.sim.rng) {
+            self.net.send_to(&Wrapper::Rng(self.sim.rng.clone()), addr);
         }
+        if let Network::Host = self.network_type {
+            self.net.send_to(&Wrapper::BossWrapper(self.sim.boss.clone()), addr);
+            self.net.send_to(&Wrapper::LevelWrapper(self.sim.level.clone()), addr);
+        }
+    }
 
-        self.bullets.retain(|bullet| bullet.pos.y > 0.0
-                            && bullet.pos.y < height
-                            && !bullet.hit);
-
-        // add delay between shots
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("couldn't get time")
-            .as_millis() as f64;
-
-        if now >= self.player_fire_delay && self.input_state.fire {
-            match self.state {
-                State::Playing | State::Won => {
-                    let bullet = self.player_ship.shoot(None, BulletType::Normal, None);
-                    self.bullets.push(bullet);
+    fn handle_network(&mut self) {
+        if let State::Loading = self.sim.state {
+            self.handle_connections();
+        }
 
-                    let msg = Wrapper::BulletWrapper(bullet);
-                    self.send_to_peers(msg);
-                    
-                },
-                _ => {}
+        // everything that arrived since the last frame
+        // a message we can't handle doesn't stop the match
+        for (msg, src) in self.net.poll() {
+            if let Err(e) = self.dispatch(msg, src) {
+                eprintln!("couldn't handle a message from {}: {}", src, e);
             }
+        }
+    }
 
-            self.player_fire_delay = now + PLAYER_FIRE_RATE;
+    fn dispatch(&mut self, msg: Wrapper, src: SocketAddr) -> Result<(), Box<dyn Error>> {
+        // leftovers from before the match, every peer computes these itself now
+        if self.lockstep_running() {
+            if let Wrapper::ShipUpdateWrapper(_) | Wrapper::BulletWrapper(_) | Wrapper::HitSignal(..)
+                | Wrapper::HealthSignal(..) | Wrapper::KillSignal(_) | Wrapper::WinSignal | Wrapper::Rng(_)
+                | Wrapper::PhaseSignal(_) | Wrapper::WaveSignal(_) = msg {
+                return Ok(())
+            }
         }
 
-        self.special_timer -= dt;
-        if self.input_state.special && self.special_timer < 0.0 {
-            let special_bullet = self.player_ship.shoot(None, BulletType::Special, None);
+        // What only the authority decides. We don't take it from anyone when
+        // we're the authority, otherwise only from the host or the server we
+        // joined.
+        if let Wrapper::StartSignal | Wrapper::WinSignal | Wrapper::AuthoritySignal | Wrapper::Rng(_)
+            | Wrapper::BossWrapper(_) | Wrapper::LevelWrapper(_) | Wrapper::HitSignal(..)
+            | Wrapper::HealthSignal(..) | Wrapper::KillSignal(_) | Wrapper::PhaseSignal(_)
+            | Wrapper::WaveSignal(_) = msg {
+            if self.sim.authoritative || Some(src) != self.host {
+                return Ok(())
+            }
+        }
 
-            let msg = Wrapper::BulletWrapper(special_bullet);
-            self.send_to_peers(msg);
+        // we decide the hits, the peers' ships, their moves and their bullets
+        // are checked like a dedicated server does. Snapshots come back here
+        // as one ShipUpdateWrapper per ship.
+        if self.is_host() {
+            if let Wrapper::ShipWrapper(_) | Wrapper::ShipUpdateWrapper(_) | Wrapper::BulletWrapper(_) = msg {
+                if let Err(violation) = self.validator.check(&msg, src, &self.sim.other_players) {
+                    self.validator.report(src, &violation);
+                    return Ok(())
+                }
+            }
+        }
 
-            self.bullets.push(special_bullet);
+        match msg {
+            Wrapper::ConnectSignal => {
+                // only the host introduces new players to everyone else
+                if let Network::Host = self.network_type {
+                    // also when we knew it, a player that restarted has
+                    // to be introduced again
+                    for peer in self.net.peers.clone() {
+                        if peer != src {
+                            self.net.send_to(&Wrapper::AddressWrapper(peer), src);
+                            self.net.send_to(&Wrapper::AddressWrapper(src), peer);
+                        }
+                    }
+                    if !self.net.peers.contains(&src) {
+                        self.net.peers.push(src);
+                    }
+                    let host = self.net.local_addr()?;
+                    self.net.send_to(&Wrapper::AddressWrapper(host), src);
+                    self.introduce(src);
 
-            self.special_timer = SPECIAL_BULLET_COOLDOWN;
+                    // a lockstep match can't be caught up with
+                    if self.lockstep.is_none() && !matches!(self.sim.state, State::Loading) {
+                        self.send_world(src);
+                    }
+                }
+            },
+            Wrapper::WorldSnapshot(world) => {
+                if let State::Loading = self.sim.state {
+                    self.sim.restore(world);
+                    self.interpolator.clear();
+
+                    // the others only hear about ships while loading
+                    if let Some(ship) = self.sim.player_ship {
+                        self.send_to_peers(Wrapper::ShipWrapper(ship));
+                        self.send_to_peers(Wrapper::PlayerInfo(ship.id, self.name.clone()));
+                    }
+                }
+            },
+            Wrapper::WorldShips(chunk) => {
+                if self.sim.authoritative {
+                    return Ok(())
+                }
+                // our ship from before we left can be in any of them
+                let own_id = self.sim.player_ship.map(|ship| ship.id);
+                let ours = chunk.departed.iter().chain(&chunk.ships).any(|ship| Some(ship.id) == own_id);
+                self.sim.restore_ships(chunk);
+                if let (true, Some(ship)) = (ours, self.sim.player_ship) {
+                    self.send_to_peers(Wrapper::ShipWrapper(ship));
+                }
+            },
+            Wrapper::AddressWrapper(address) => {
+                // a host bound to every interface doesn't know
+                // which of its addresses we reached it on
+                let address = if address.ip().is_unspecified() { src } else { address };
+                self.add_peer(address);
+            },
+            Wrapper::AddressesWrapper(addresses) => {
+                for address in addresses {
+                    self.add_peer(address);
+                }
+            },
+            Wrapper::ShipWrapper(ship) => {
+                self.owners.insert(ship.id, src);
+                self.sim.add_player(ship);
+            },
+            Wrapper::ShipUpdateWrapper(ship_update) => {
+                match (&mut self.predictor, &mut self.sim.player_ship) {
+                    (Some(predictor), Some(ship)) if ship.id == ship_update.id => {
+                        predictor.reconcile(ship, &ship_update, self.sim.width, self.sim.height);
+                    },
+                    _ => {
+                        let pos = Point { x: ship_update.x, y: ship_update.y };
+                        self.interpolator.push(ship_update.id, self.sim.time(), pos);
+                        self.sim.apply_ship_update(&ship_update);
+                    },
+                }
+            },
+            Wrapper::SnapshotWrapper(snapshot) => {
+                let (ack, updates) = self.snapshots.receive(&snapshot, src);
+                if let Some(ack) = ack {
+                    self.net.send_to(&ack, src);
+                }
+                for update in updates {
+                    self.dispatch(Wrapper::ShipUpdateWrapper(update), src)?;
+                }
+            },
+            Wrapper::SnapshotAck(seq) => self.snapshots.acknowledge(src, seq),
+            Wrapper::BulletWrapper(bullet) => self.sim.bullets.push(bullet),
+            // the host owns the rng
+            Wrapper::Rng(rng) => self.sim.rng = rng,
+            // the host picks it, the server counts as one
+            Wrapper::BossWrapper(boss) => {
+                match boss.validate() {
+                    Ok(()) => self.sim.set_boss(boss),
+                    Err(e) => eprintln!("ignored the boss from {}: {}", src, e),
+                }
+            },
+            // the host sends it after the boss, its enemies can fire the boss's bullets
+            Wrapper::LevelWrapper(level) => {
+                match level.validate(&self.sim.boss) {
+                    Ok(()) => self.sim.set_level(level),
+                    Err(e) => eprintln!("ignored the level from {}: {}", src, e),
+                }
+            },
+            Wrapper::StartSignal => {
+                if let State::Loading = self.sim.state {
+                    self.sim.state = State::Playing;
+                }
+            },
+            Wrapper::RestartSignal => {
+                if Some(src) == self.host {
+                    self.reset();
+                }
+            },
+            Wrapper::WinSignal => self.sim.state = State::Won,
+            Wrapper::HitSignal(ship_id, bullet_id) => self.sim.apply_hit(ship_id, bullet_id),
+            Wrapper::HealthSignal(id, health) => self.sim.apply_health(id, health),
+            Wrapper::KillSignal(id) => self.announce_kill(id),
+            Wrapper::PhaseSignal(phase) => self.sim.start_boss_phase(phase),
+            Wrapper::WaveSignal(wave) => self.sim.start_wave(wave),
+            Wrapper::PlayerInfo(id, name) => {
+                self.names.insert(id, name);
+            },
+            Wrapper::AuthoritySignal => {
+                self.sim.authoritative = false;
+                if self.predictor.is_none() {
+                    self.predictor = Some(Predictor::new());
+                }
+            },
+            // inputs are for the server
+            Wrapper::InputWrapper(..) => {},
+            Wrapper::LockstepInput(id, frame, input) => {
+                if let Some(lockstep) = &mut self.lockstep {
+                    lockstep.receive(id, frame, input);
+                }
+            },
+            Wrapper::LockstepStart(players, rng) => self.start_lockstep(players, rng),
+            Wrapper::ReadySignal(id, ready) => {
+                self.ready.insert(id, ready);
+            },
+            Wrapper::DisconnectSignal(id) => {
+                // with a dedicated server the message is relayed, the server stays
+                let direct = self.owners.get(&id) == Some(&src);
+                if direct && self.predictor.is_none() {
+                    self.net.remove_peer(src);
+                    self.snapshots.remove_peer(src);
+                }
+                self.remove_player(id, "left");
+            },
+            // handled by the endpoint
+            Wrapper::Heartbeat => {},
+            Wrapper::StateChecksum(id, checksum) => {
+                if self.lockstep_running() {
+                    self.desync.receive(id, checksum);
+                }
+            },
         }
 
-        self.shield_timer -= dt;
-        if self.input_state.shield && self.shield_timer < 0.0 {
-            self.player_ship.shield = true;
-            self.shield_timer = SHIELD_COOLDOWN;
-            self.shield_active = SHIELD_DURATION;
+        Ok(())
+    }
 
-            let update = Wrapper::ShipUpdateWrapper(ShipUpdate::new(
-                    self.player_ship.id,
-                    self.player_ship.pos.x,
-                    self.player_ship.pos.y,
-                    true));
-            self.send_to_peers(update);
+    // everything a player joining late needs
+    fn send_world(&mut self, addr: SocketAddr) {
+        let (world, chunks) = self.sim.world();
+        self.net.send_to(&Wrapper::WorldSnapshot(world), addr);
+        for chunk in chunks {
+            self.net.send_to(&Wrapper::WorldShips(chunk), addr);
+        }
+        for bullet in self.sim.bullets.clone() {
+            self.net.send_to(&Wrapper::BulletWrapper(bullet), addr);
         }
 
-        if self.shield_active > 0.0 {
-            self.shield_active -= dt;
+        let mut names: Vec<(Uuid, String)> = self.names.clone().into_iter().collect();
+        if let Some(ship) = self.sim.player_ship {
+            names.push((ship.id, self.name.clone()));
         }
-        else if self.player_ship.shield {
-            self.player_ship.shield = false;
-            let update = Wrapper::ShipUpdateWrapper(ShipUpdate::new(
-                    self.player_ship.id,
-                    self.player_ship.pos.x,
-                    self.player_ship.pos.y,
-                    self.player_ship.shield));
-            self.send_to_peers(update);
+        for (id, name) in names {
+            self.net.send_to(&Wrapper::PlayerInfo(id, name), addr);
         }
+    }
 
-        if now >= self.enemy_fire_delay {
-            match self.state {
-                State::Loading => {},
-                State::Won => {},
-                _ => {
-                    let mut rng = self.rng.clone().unwrap();
-
-                    self.bullets.push(self.enemy_ship.shoot(Some(consts::PI/4.0), BulletType::Normal,Some(rng.gen::<u64>())));
-                    self.bullets.push(self.enemy_ship.shoot(Some(-1.0 * consts::PI/4.0), BulletType::Normal,Some(rng.gen::<u64>())));
-                    self.bullets.push(self.enemy_ship.shoot(None, BulletType::Normal, Some(rng.gen::<u64>())));
-
-
-                    let rand_angle = rng.gen_range(-1.0 * consts::PI/4.0, consts::PI/4.0);
-                    let bullet = self.enemy_ship.shoot(Some(rand_angle), BulletType::Normal, Some(rng.gen::<u64>()));
-                    self.bullets.push(bullet);
-
-                    rng.jump();
-
-                    if self.enemy_ship.health < BOSS_HEALTH/2.0 {
-                        let rand_angle2 = rand::thread_rng().gen_range(-1.0 * consts::PI/4.0, consts::PI/4.0);
-
-                        let bullet = self.enemy_ship.shoot(Some(rand_angle2), BulletType::Normal, Some(rng.gen::<u64>()));
-                        self.bullets.push(bullet);
-
-                        let msg = Wrapper::BulletWrapper(bullet);
-                        self.send_to_peers(msg);
+    // introduced even when we knew it, it may have restarted
+    fn add_peer(&mut self, address: SocketAddr) {
+        if !self.net.peers.contains(&address) {
+            self.net.peers.push(address);
+        }
+        self.introduce(address)
+    }
+}
 
-                    }
-                    rng.jump();
-                    self.rng = Some(rng);
+impl EventHandler for MainState {
+    fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
 
-                }
+        let dt = ggez::timer::duration_to_f64(ggez::timer::delta(ctx)) as f32;
 
+        self.broadcast_timer -= dt;
+        self.validator.update(dt);
+        for addr in self.net.update(dt) {
+            self.snapshots.remove_peer(addr);
+            let ids: Vec<Uuid> = self.owners
+                .iter()
+                .filter(|(_, &owner)| owner == addr)
+                .map(|(&id, _)| id)
+                .collect();
+            for id in ids {
+                self.remove_player(id, "timed out");
             }
+        }
+
+        for notice in &mut self.notices {
+            notice.1 -= dt;
+        }
+        self.notices.retain(|(_, timer)| *timer > 0.0);
 
-            self.enemy_fire_delay = now + BOSS_FIRE_RATE;
+        if let Some(browser) = &mut self.browser {
+            browser.update(dt);
+            return Ok(())
         }
 
+        if let Some(beacon) = &self.beacon {
+            beacon.answer(&self.announcement());
+        }
 
-        self.check_collisions();
+        // fixed frames, only inputs are exchanged
+        if self.lockstep_running() {
+            self.update_lockstep(dt);
+            self.handle_network();
+            return Ok(())
+        }
 
+        let (width, height) = graphics::drawable_size(ctx);
+        self.sim.resize(width, height);
 
-        if let State::Playing = self.state {
-            if self.player_ship.health < 0.0 {
-                self.state = State::Lost;
+        for msg in self.sim.step(dt, &self.input_state) {
+            if let Wrapper::KillSignal(id) = msg {
+                self.announce_kill(id);
             }
-            else if self.enemy_ship.health < 0.1 {
-                self.state = State::Won;
-                let msg  = Wrapper::WinSignal;
-                self.send_to_peers(msg);
+            self.send_to_peers(msg);
+        }
 
+        if let (Some(predictor), Some(ship)) = (&mut self.predictor, self.sim.player_ship) {
+            // the server moves our ship, it only needs our inputs
+            predictor.record(dt, self.input_state);
+            let msg = Wrapper::InputWrapper(ship.id, predictor.unacknowledged());
+            self.send_to_peers(msg);
+        }
+        // broadcast_timer limits the amount of time the position of the ship gets broadcasted
+        // to all peers, and only to the ones that haven't seen it yet
+        else if let Some(ship) = self.sim.player_ship {
+            if self.broadcast_timer < 0.0 {
+                self.snapshots.send(&mut self.net, vec![ShipState::new(&ship, None)]);
+                self.broadcast_timer = BROADCAST_TICK;
             }
         }
 
@@ -513,32 +861,75 @@ impl EventHandler for MainState {
         //         NETWORKING
         // ==================================
 
-        match self.state {
-            State::Loading => self.handle_connections(),
-            _ => self.handle_updates()
-        }.unwrap();
+        self.handle_network();
+
 
-        
         Ok(())
     }
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         graphics::clear(ctx, graphics::BLACK);
 
-        self.player_ship.draw(&mut self.assets, ctx, Some(1))?;
-        self.enemy_ship.draw(&mut self.assets, ctx, None)?;
+        if self.browser.is_some() {
+            self.draw_browser(ctx)?;
+            self.draw_notices(ctx)?;
+            self.draw_netsim_panel(ctx)?;
+            graphics::present(ctx)?;
+            ggez::timer::yield_now();
+            return Ok(())
+        }
+
+        if let Some(ship) = &self.sim.player_ship {
+            ship.draw(&mut self.assets, ctx, Some(1))?;
+        }
+        self.assets.use_boss_sprite(ctx, &self.sim.boss.sprite);
+        let boss = self.interpolated(&self.sim.enemy_ship);
+        let boss = match self.sim.boss_state() {
+            Some(state) => state.animate(&boss, &self.sim.boss),
+            None if self.sim.boss_arrived() => Some(boss),
+            // still behind the waves
+            None => None,
+        };
+        if let Some(boss) = boss {
+            boss.draw(&mut self.assets, ctx, None)?;
+        }
+
+        let enemies: Vec<Ship> = self.sim.enemies
+            .iter()
+            .map(|enemy| self.interpolated(&enemy.ship))
+            .collect();
+        for (ship, enemy) in enemies.iter().zip(&self.sim.enemies) {
+            ship.draw_enemy(&self.sim.level.enemies[enemy.kind], &mut self.assets, ctx)?;
+        }
+
+        let other_players: Vec<Ship> = self.sim.other_players
+            .iter()
+            .map(|ship| self.interpolated(ship))
+            .collect();
 
-        for ship in &self.other_players {
+        for ship in &other_players {
             ship.draw(&mut self.assets, ctx, Some(2))?;
+
+            if let Some(name) = self.names.get(&ship.id) {
+                let text = graphics::Text::new((name.as_str(),self.assets.font,8.0));
+                let x = ship.pos.x - text.width(ctx) as f32/2.0;
+                graphics::draw(ctx, &text, (mint::Point2{x,y:ship.pos.y+24.0}, 0.0, graphics::WHITE))?;
+            }
         }
 
-        for bullet in &self.bullets {
-            bullet.draw(&mut self.assets, ctx)?;
+        for bullet in &self.sim.bullets {
+            bullet.draw(self.sim.bullet_types.get(bullet.bullet_type), &mut self.assets, ctx)?;
         }
 
         self.draw_indicators(ctx)?;
+        self.draw_stats(ctx)?;
+        self.draw_notices(ctx)?;
+        self.draw_netsim_panel(ctx)?;
 
-        match self.state {
+        match self.sim.state {
+            State::Loading => {
+                self.draw_lobby(ctx)
+            },
             State::Won => {
                 self.draw_win_screen(ctx)
             },
@@ -548,6 +939,10 @@ impl EventHandler for MainState {
             _ => Ok(()),
         }?;
 
+        if let Some(reason) = self.net.rejected.clone() {
+            self.draw_rejection(ctx, &reason)?;
+        }
+
         graphics::present(ctx)?;
 
         ggez::timer::yield_now();
@@ -557,17 +952,50 @@ impl EventHandler for MainState {
 
     fn key_down_event(&mut self, ctx: &mut Context, key: KeyCode, _mods: KeyMods, _: bool) {
 
+            if key == KeyCode::F12 {
+                self.stats_overlay = !self.stats_overlay;
+                return
+            }
+            if self.netsim_key(key) {
+                return
+            }
+
+            if let Some(browser) = &mut self.browser {
+                match key {
+                    KeyCode::Q => ggez::event::quit(ctx),
+                    KeyCode::Up | KeyCode::W => browser.select_previous(),
+                    KeyCode::Down | KeyCode::S => browser.select_next(),
+                    KeyCode::Return => self.join_selected(),
+                    _ => {},
+                }
+                return
+            }
+
             match key {
-                KeyCode::Q => ggez::event::quit(ctx),
-                KeyCode::R => {
-                    let signal = Wrapper::RestartSignal;
-                    self.send_to_peers(signal);
+                KeyCode::Q => {
+                    self.disconnect();
+                    ggez::event::quit(ctx);
+                },
+                // a dedicated server restarts everyone, us included, if
+                // we're the player allowed to
+                KeyCode::R if self.is_host() => {
+                    self.send_to_peers(Wrapper::RestartSignal);
                     self.reset();
                 },
+                KeyCode::R if self.predictor.is_some() => self.send_to_peers(Wrapper::RestartSignal),
                 _ => {},
             }
 
-            if self.player_ship.health > 0.0 {
+            // the lobby
+            if let State::Loading = self.sim.state {
+                match key {
+                    KeyCode::Return => self.toggle_ready(),
+                    KeyCode::Space if self.is_host() && self.everyone_ready() => self.start(),
+                    _ => {},
+                }
+            }
+
+            if self.sim.player_alive() {
                 match key {
                     KeyCode::W => self.input_state.up = true,
                     KeyCode::S => self.input_state.down = true,
@@ -579,26 +1007,14 @@ impl EventHandler for MainState {
 
                     _ => {},
                 }
-
-                match key {
-                    KeyCode::W | KeyCode::S | KeyCode::A | KeyCode::D | KeyCode::Space | KeyCode::J | KeyCode::K => {
-                        match self.state {
-                            State::Loading => {
-
-                                let signal = Wrapper::StartSignal;
-                                self.send_to_peers(signal);
-
-                                self.state = State::Playing;
-                            
-                            },
-                            _ => {},
-                        }
-                    }
-
-                    _ => {},
-                }
             }
     }
+    // closing the window
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        self.disconnect();
+        false
+    }
+
     fn key_up_event(&mut self, _ctx: &mut Context, key: KeyCode, _mods: KeyMods) {
 
         match key {
@@ -0,0 +1,181 @@
+use crate::constants::*;
+use crate::network::Wrapper;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+
+//=================
+//   PEER STATS
+//=================
+
+// counted since the start of the current window
+#[derive(Default, Clone, Copy)]
+struct Counters {
+    bytes_in: usize,
+    bytes_out: usize,
+    reliable_sent: u32,
+    resent: u32,
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct PeerStats {
+    // smoothed round trip time, in seconds
+    pub rtt: Option<f32>,
+    // share of the reliable packets that had to be resent, between 0 and 1
+    pub loss: f32,
+    // per second, over the last window
+    pub bytes_in: f32,
+    pub bytes_out: f32,
+    // seconds since the peer's last snapshot
+    pub snapshot_age: Option<f32>,
+}
+
+//=================
+//     STATS
+//=================
+
+// What the endpoint sees of the traffic, for the overlay and the server's log.
+#[derive(Default)]
+pub struct Stats {
+    window: f32,
+    counters: HashMap<SocketAddr, Counters>,
+    peers: HashMap<SocketAddr, PeerStats>,
+    // messages by Wrapper variant since the start
+    messages_in: BTreeMap<&'static str, u64>,
+    messages_out: BTreeMap<&'static str, u64>,
+    // datagrams that weren't valid packets, since the start
+    dropped: u64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    pub fn peer(&self, addr: SocketAddr) -> Option<&PeerStats> {
+        self.peers.get(&addr)
+    }
+
+    // sorted so the overlay doesn't shuffle its lines
+    pub fn peers(&self) -> Vec<(SocketAddr, PeerStats)> {
+        let mut peers: Vec<(SocketAddr, PeerStats)> = self.peers
+            .iter()
+            .map(|(&addr, &stats)| (addr, stats))
+            .collect();
+        peers.sort_by_key(|(addr, _)| *addr);
+        peers
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // every kind of message seen, with how many were received and sent
+    pub fn message_counts(&self) -> Vec<(&'static str, u64, u64)> {
+        self.messages_in
+            .keys()
+            .chain(self.messages_out.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|&name| (name,
+                          *self.messages_in.get(name).unwrap_or(&0),
+                          *self.messages_out.get(name).unwrap_or(&0)))
+            .collect()
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        for stats in self.peers.values_mut() {
+            if let Some(age) = &mut stats.snapshot_age {
+                *age += dt;
+            }
+        }
+
+        self.window += dt;
+        if self.window < STATS_WINDOW {
+            return
+        }
+
+        for (addr, counters) in self.counters.drain() {
+            let stats = self.peers.entry(addr).or_default();
+            stats.bytes_in = counters.bytes_in as f32 / self.window;
+            stats.bytes_out = counters.bytes_out as f32 / self.window;
+            // nothing reliable was sent, the last estimate is as good as any
+            if counters.reliable_sent > 0 {
+                stats.loss = (counters.resent as f32 / counters.reliable_sent as f32).min(1.0);
+            }
+        }
+        self.window = 0.0;
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        self.counters.remove(&addr);
+        self.peers.remove(&addr);
+    }
+
+    pub(crate) fn sent(&mut self, addr: SocketAddr, bytes: usize) {
+        self.counters.entry(addr).or_default().bytes_out += bytes;
+    }
+
+    pub(crate) fn received(&mut self, addr: SocketAddr, bytes: usize) {
+        self.counters.entry(addr).or_default().bytes_in += bytes;
+    }
+
+    pub(crate) fn sent_message(&mut self, addr: SocketAddr, msg: &Wrapper) {
+        *self.messages_out.entry(msg.name()).or_insert(0) += 1;
+        if msg.channel().is_some() {
+            self.counters.entry(addr).or_default().reliable_sent += 1;
+        }
+    }
+
+    pub(crate) fn received_message(&mut self, addr: SocketAddr, msg: &Wrapper) {
+        *self.messages_in.entry(msg.name()).or_insert(0) += 1;
+        if let Wrapper::SnapshotWrapper(_) = msg {
+            self.peers.entry(addr).or_default().snapshot_age = Some(0.0);
+        }
+    }
+
+    pub(crate) fn dropped_datagram(&mut self) {
+        self.dropped += 1;
+    }
+
+    pub(crate) fn resent(&mut self, addr: SocketAddr) {
+        self.counters.entry(addr).or_default().resent += 1;
+    }
+
+    pub(crate) fn rtt_sample(&mut self, addr: SocketAddr, sample: f32) {
+        let stats = self.peers.entry(addr).or_default();
+        let rtt = stats.rtt.get_or_insert(sample);
+        *rtt += (sample - *rtt) * RTT_SMOOTHING;
+    }
+}
+
+// one line per peer then the message counts, for a log
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, stats) in self.peers() {
+            writeln!(f, "{}  {}", addr, stats)?;
+        }
+        let counts: Vec<String> = self.message_counts()
+            .iter()
+            .map(|(name, received, sent)| format!("{} {}/{}", name, received, sent))
+            .collect();
+        writeln!(f, "messages in/out: {}", counts.join(", "))?;
+        write!(f, "dropped datagrams: {}", self.dropped)
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "rtt {:.0}ms", rtt * 1000.0)?,
+            None => write!(f, "rtt -")?,
+        }
+        write!(f, "  loss {:.0}%  in {:.1}KB/s  out {:.1}KB/s",
+               self.loss * 100.0, self.bytes_in / 1000.0, self.bytes_out / 1000.0)?;
+        match self.snapshot_age {
+            Some(age) => write!(f, "  snapshot {:.0}ms", age * 1000.0),
+            None => write!(f, "  snapshot -"),
+        }
+    }
+}
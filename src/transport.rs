@@ -0,0 +1,273 @@
+use crate::constants::*;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{UdpSocket, TcpListener, TcpStream, SocketAddr};
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
+use std::time::Instant;
+
+use tungstenite::{WebSocket, Message};
+use tungstenite::handshake::server::{Request, Response};
+
+//=================
+//   TRANSPORT
+//=================
+
+// Peers are told apart by an address, whatever actually carries the
+// packets. The rest of the game keeps them in maps and sends them around.
+pub type PeerId = SocketAddr;
+
+// Moves datagrams between us and the peers. Like UDP, a datagram can get
+// lost and nothing is connected: sending to a peer is enough to reach it.
+pub trait Transport {
+    fn send(&mut self, data: &[u8], peer: PeerId) -> io::Result<()>;
+    // fails with WouldBlock when nothing is waiting
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerId)>;
+    // the id the peers know us by
+    fn local_id(&self) -> io::Result<PeerId>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportKind {
+    Udp,
+    WebSocket,
+}
+
+// the transport every peer of a match has to use, on the given address
+pub fn bind(kind: TransportKind, addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+    match kind {
+        TransportKind::Udp => {
+            let socket = UdpSocket::bind(addr)?;
+            socket.set_nonblocking(true)?;
+            Ok(Box::new(socket))
+        },
+        TransportKind::WebSocket => Ok(Box::new(WebSocketTransport::bind(addr)?)),
+    }
+}
+
+//=================
+//      UDP
+//=================
+
+impl Transport for UdpSocket {
+    fn send(&mut self, data: &[u8], peer: PeerId) -> io::Result<()> {
+        self.send_to(data, peer).map(|_| ())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerId)> {
+        self.recv_from(buf)
+    }
+
+    fn local_id(&self) -> io::Result<PeerId> {
+        self.local_addr()
+    }
+}
+
+//=================
+//    CHANNEL
+//=================
+
+// Two ends connected in memory, to run an endpoint without a network.
+// Each end is known to the other by the id it was given.
+pub struct ChannelTransport {
+    id: PeerId,
+    peer: PeerId,
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl ChannelTransport {
+    pub fn pair(a: PeerId, b: PeerId) -> (ChannelTransport, ChannelTransport) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        let a_end = ChannelTransport { id: a, peer: b, sender: a_sender, receiver: a_receiver };
+        let b_end = ChannelTransport { id: b, peer: a, sender: b_sender, receiver: b_receiver };
+        (a_end, b_end)
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, data: &[u8], peer: PeerId) -> io::Result<()> {
+        // like UDP, what is sent to nobody just vanishes
+        if peer == self.peer {
+            let _ = self.sender.send(data.to_vec());
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerId)> {
+        match self.receiver.try_recv() {
+            Ok(data) => {
+                // truncated like a datagram bigger than the buffer
+                let amt = data.len().min(buf.len());
+                buf[..amt].copy_from_slice(&data[..amt]);
+                Ok((amt, self.peer))
+            },
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_id(&self) -> io::Result<PeerId> {
+        Ok(self.id)
+    }
+}
+
+//=================
+//   WEBSOCKET
+//=================
+
+// Every datagram is a binary message. We listen for the peers and connect
+// to the ones we send to first, so it works the same both ways. A peer
+// gives the port it listens on in the path of the request (`/7778`) so it
+// has the same id whoever connected. Browsers can't listen, they connect
+// to `/` and are known by the address of their connection.
+pub struct WebSocketTransport {
+    listener: TcpListener,
+    connections: HashMap<PeerId, WebSocket<TcpStream>>,
+    // when we last failed to reach a peer, connecting blocks the game
+    failed: HashMap<PeerId, Instant>,
+}
+
+impl WebSocketTransport {
+    pub fn bind(addr: SocketAddr) -> io::Result<WebSocketTransport> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(WebSocketTransport {
+            listener,
+            connections: HashMap::new(),
+            failed: HashMap::new(),
+        })
+    }
+
+    fn accept(&mut self) {
+        while let Ok((stream, remote)) = self.listener.accept() {
+            match handshake(stream) {
+                Ok((ws, port)) => {
+                    // a peer connecting again replaces its old connection
+                    let id = SocketAddr::new(remote.ip(), port.unwrap_or_else(|| remote.port()));
+                    self.connections.insert(id, ws);
+                },
+                Err(e) => eprintln!("websocket handshake with {} failed: {}", remote, e),
+            }
+        }
+    }
+
+    fn connect(&mut self, peer: PeerId) -> io::Result<()> {
+        if self.failed.get(&peer).is_some_and(|at| at.elapsed().as_secs_f32() < WEBSOCKET_RETRY) {
+            return Err(io::Error::from(io::ErrorKind::NotConnected))
+        }
+
+        let port = self.listener.local_addr()?.port();
+        let connection = TcpStream::connect_timeout(&peer, WEBSOCKET_HANDSHAKE_TIMEOUT)
+            .and_then(|stream| prepare(&stream).map(|_| stream))
+            .and_then(|stream| {
+                tungstenite::client(format!("ws://{}/{}", peer, port), stream)
+                    .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))
+            })
+            .and_then(|(ws, _)| ws.get_ref().set_nonblocking(true).map(|_| ws));
+
+        match connection {
+            Ok(ws) => {
+                self.failed.remove(&peer);
+                self.connections.insert(peer, ws);
+                Ok(())
+            },
+            Err(e) => {
+                self.failed.insert(peer, Instant::now());
+                Err(e)
+            },
+        }
+    }
+}
+
+// Our side of a handshake started by a peer. Also returns the port the peer
+// listens on, when it gave one. The callback's error type is tungstenite's.
+#[allow(clippy::result_large_err)]
+fn handshake(stream: TcpStream) -> io::Result<(WebSocket<TcpStream>, Option<u16>)> {
+    prepare(&stream)?;
+    let mut port = None;
+    let ws = tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+        port = request.uri().path().trim_start_matches('/').parse().ok();
+        Ok(response)
+    }).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    ws.get_ref().set_nonblocking(true)?;
+    Ok((ws, port))
+}
+
+// the handshake is done blocking, but not for long
+fn prepare(stream: &TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(WEBSOCKET_HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBSOCKET_HANDSHAKE_TIMEOUT))
+}
+
+// what's left of a tungstenite error once a WouldBlock is taken care of
+fn into_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        _ => io::Error::from(io::ErrorKind::ConnectionAborted),
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send(&mut self, data: &[u8], peer: PeerId) -> io::Result<()> {
+        if !self.connections.contains_key(&peer) {
+            self.connect(peer)?;
+        }
+
+        let ws = self.connections.get_mut(&peer).unwrap();
+        match ws.send(Message::Binary(data.to_vec())) {
+            Ok(()) => Ok(()),
+            // queued, goes out with the next flush
+            Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => {
+                self.connections.remove(&peer);
+                Err(into_io(e))
+            },
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, PeerId)> {
+        self.accept();
+
+        let mut closed = Vec::new();
+        let mut received = None;
+        for (&peer, ws) in self.connections.iter_mut() {
+            loop {
+                match ws.read() {
+                    Ok(Message::Binary(data)) => {
+                        let amt = data.len().min(buf.len());
+                        buf[..amt].copy_from_slice(&data[..amt]);
+                        received = Some((amt, peer));
+                        break
+                    },
+                    // pings are answered by tungstenite, text isn't ours
+                    Ok(_) => continue,
+                    Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // what didn't fit in the socket when it was sent
+                        let _ = ws.flush();
+                        break
+                    },
+                    Err(_) => {
+                        closed.push(peer);
+                        break
+                    },
+                }
+            }
+            if received.is_some() {
+                break
+            }
+        }
+
+        // the endpoint times the peer out if it doesn't come back
+        for peer in closed {
+            self.connections.remove(&peer);
+        }
+        received.ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))
+    }
+
+    fn local_id(&self) -> io::Result<PeerId> {
+        self.listener.local_addr()
+    }
+}
@@ -0,0 +1,382 @@
+use crate::constants::*;
+use crate::bullets::BulletType;
+use crate::entities::{ Ship, Possession };
+use crate::level;
+use crate::network::Wrapper;
+use crate::{ distance_2d, Point };
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+
+use uuid::Uuid;
+
+//=================
+//   VIOLATIONS
+//=================
+
+#[derive(Debug)]
+pub enum Violation {
+    // the ship belongs to another address
+    NotOwner(Uuid),
+    // sent before claiming a ship with a ShipWrapper
+    NoShip,
+    // the boss or an enemy
+    NotAPlayer(Uuid),
+    // more health than a ship spawns with
+    Health(f32),
+    // an address plays one ship
+    SecondShip(Uuid),
+    TooFast { distance: f32, allowed: f32 },
+    FireRate,
+    SpecialCooldown,
+    EnemyBullet,
+    BulletTooFar(f32),
+    // a player bullet that already moved, went through a ship or waits before moving
+    BulletMotion,
+    // a boss's kind of bullet
+    ForbiddenBullet,
+    // a coordinate that isn't a number, it would pass every distance check
+    NotFinite,
+    // more commands in one packet than a player resends
+    TooManyInputs(usize),
+    InputDuration(f32),
+    // more input than time went by
+    InputBudget,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::NotOwner(id) => write!(f, "ship {} belongs to someone else", id),
+            Violation::NoShip => write!(f, "no ship claimed yet"),
+            Violation::NotAPlayer(id) => write!(f, "ship {} isn't a player's", id),
+            Violation::Health(health) => write!(f, "ship with {} health", health),
+            Violation::SecondShip(id) => write!(f, "claimed ship {} while playing another one", id),
+            Violation::TooFast { distance, allowed } => write!(f, "moved {:.0}px, at most {:.0}px allowed", distance, allowed),
+            Violation::FireRate => write!(f, "shooting faster than the fire rate"),
+            Violation::SpecialCooldown => write!(f, "special used during its cooldown"),
+            Violation::EnemyBullet => write!(f, "sent a boss bullet"),
+            Violation::BulletTooFar(distance) => write!(f, "bullet spawned {:.0}px away from its ship", distance),
+            Violation::BulletMotion => write!(f, "bullet moving unlike its type"),
+            Violation::ForbiddenBullet => write!(f, "fired a bullet players don't have"),
+            Violation::NotFinite => write!(f, "sent a position that isn't a number"),
+            Violation::TooManyInputs(count) => write!(f, "{} inputs in one packet", count),
+            Violation::InputDuration(dt) => write!(f, "input lasting {}s", dt),
+            Violation::InputBudget => write!(f, "sending input faster than time goes by"),
+        }
+    }
+}
+
+//=================
+//   VALIDATOR
+//=================
+
+// what we remember about every address
+struct Sender {
+    // normal shots that can be fired right now, refills at the fire rate.
+    // A few can be saved up since packets tend to arrive in bursts.
+    shots: f32,
+    last_special: Option<f32>,
+    // last accepted position of its ship and when it got there
+    last_pos: Option<(Point, f32)>,
+    // seconds of input that can be applied right now, refills with the
+    // server's clock so a player can't speed its ship up
+    input_time: f32,
+    // rate limiting of the log
+    log_timer: f32,
+    suppressed: u32,
+}
+
+impl Default for Sender {
+    fn default() -> Sender {
+        Sender {
+            shots: MAX_SAVED_SHOTS,
+            last_special: None,
+            last_pos: None,
+            input_time: MAX_INPUT_BACKLOG,
+            log_timer: 0.0,
+            suppressed: 0,
+        }
+    }
+}
+
+// Checks what the players send the dedicated server, or the host of a peer
+// to peer match, before it's applied or relayed: a player can only speak for
+// its own ship, can't move faster than a ship does and can't shoot faster
+// than the cooldowns allow.
+#[derive(Default)]
+pub struct Validator {
+    // seconds since the server started
+    time: f32,
+    owners: HashMap<Uuid, SocketAddr>,
+    senders: HashMap<SocketAddr, Sender>,
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator::default()
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt;
+        let refill = dt * 1000.0 / PLAYER_FIRE_RATE as f32;
+        for sender in self.senders.values_mut() {
+            sender.shots = (sender.shots + refill).min(MAX_SAVED_SHOTS);
+            sender.input_time = (sender.input_time + dt).min(MAX_INPUT_BACKLOG);
+            sender.log_timer -= dt;
+        }
+    }
+
+    // ids of the ships played from this address
+    pub fn owned_by(&self, addr: SocketAddr) -> Vec<Uuid> {
+        self.owners
+            .iter()
+            .filter(|(_, &owner)| owner == addr)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    // claims are limited to one, there's no picking between ships
+    fn ship_of(&self, addr: SocketAddr) -> Option<Uuid> {
+        self.owners
+            .iter()
+            .find(|(_, &owner)| owner == addr)
+            .map(|(&id, _)| id)
+    }
+
+    // The first address to send a ship owns it until the ship is released,
+    // an address can only own one. The boss and the enemies are nobody's.
+    // Returns true when the ship changed hands.
+    pub fn claim(&mut self, id: Uuid, src: SocketAddr) -> Result<bool, Violation> {
+        if id.is_nil() || level::is_enemy_id(id) {
+            return Err(Violation::NotAPlayer(id))
+        }
+        if self.owned_by(src).iter().any(|&owned| owned != id) {
+            return Err(Violation::SecondShip(id))
+        }
+        match self.owners.get(&id) {
+            Some(&owner) if owner == src => Ok(false),
+            Some(_) => Err(Violation::NotOwner(id)),
+            None => {
+                self.owners.insert(id, src);
+                self.senders.entry(src).or_default().last_pos = None;
+                Ok(true)
+            },
+        }
+    }
+
+    // the player left, its ship can be claimed again by a rejoining player
+    pub fn release(&mut self, id: Uuid) {
+        if let Some(addr) = self.owners.remove(&id) {
+            if self.owned_by(addr).is_empty() {
+                self.senders.remove(&addr);
+            }
+        }
+    }
+
+    // `ships` are the players' ships as the server knows them
+    pub fn check(&mut self, msg: &Wrapper, src: SocketAddr, ships: &[Ship]) -> Result<(), Violation> {
+        match msg {
+            Wrapper::ShipWrapper(ship) => {
+                if let Possession::Enemy = ship.ship_type {
+                    return Err(Violation::NotAPlayer(ship.id))
+                }
+                if !finite(ship.pos) {
+                    return Err(Violation::NotFinite)
+                }
+                if ship.health > PLAYER_HEALTH || !ship.health.is_finite() {
+                    return Err(Violation::Health(ship.health))
+                }
+                self.claim(ship.id, src).map(|_| ())
+            },
+            Wrapper::ShipUpdateWrapper(update) => {
+                self.check_owner(update.id, src)?;
+                self.check_movement(src, Point { x: update.x, y: update.y })
+            },
+            Wrapper::InputWrapper(id, commands) => {
+                self.check_owner(*id, src)?;
+                if commands.len() > INPUT_REDUNDANCY {
+                    return Err(Violation::TooManyInputs(commands.len()))
+                }
+                match commands.iter().find(|command| !(command.dt > 0.0 && command.dt.is_finite())) {
+                    Some(command) => Err(Violation::InputDuration(command.dt)),
+                    None => Ok(()),
+                }
+            },
+            Wrapper::PlayerInfo(id, _)
+                | Wrapper::ReadySignal(id, _)
+                | Wrapper::DisconnectSignal(id) => self.check_owner(*id, src),
+            Wrapper::BulletWrapper(bullet) => {
+                if let Possession::Enemy = bullet.possession {
+                    return Err(Violation::EnemyBullet)
+                }
+                if !bullet.is_fresh() {
+                    return Err(Violation::BulletMotion)
+                }
+                if !finite(bullet.pos) || !bullet.angle.is_finite() {
+                    return Err(Violation::NotFinite)
+                }
+
+                // the one ship of the sender, as we know it
+                let id = self.ship_of(src).ok_or(Violation::NoShip)?;
+                let ship = ships.iter().find(|ship| ship.id == id).ok_or(Violation::NoShip)?;
+                let distance = distance_2d(bullet.pos, ship.pos);
+                if distance > MAX_BULLET_DISTANCE {
+                    return Err(Violation::BulletTooFar(distance))
+                }
+
+                let time = self.time;
+                let sender = self.senders.entry(src).or_default();
+                match bullet.bullet_type {
+                    BulletType::NORMAL => {
+                        if sender.shots < 1.0 {
+                            return Err(Violation::FireRate)
+                        }
+                        sender.shots -= 1.0;
+                    },
+                    BulletType::SPECIAL => {
+                        // a little leeway for the packets that got delayed
+                        let cooldown = SPECIAL_BULLET_COOLDOWN * COOLDOWN_TOLERANCE;
+                        if sender.last_special.is_some_and(|last| time - last < cooldown) {
+                            return Err(Violation::SpecialCooldown)
+                        }
+                        sender.last_special = Some(time);
+                    },
+                    _ => return Err(Violation::ForbiddenBullet),
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Called for every new input before it's applied, the ones it refuses
+    // are sent again by the player with the next packets.
+    pub fn spend_input(&mut self, src: SocketAddr, dt: f32) -> Result<(), Violation> {
+        let sender = self.senders.entry(src).or_default();
+        if sender.input_time < dt {
+            return Err(Violation::InputBudget)
+        }
+        sender.input_time -= dt;
+        Ok(())
+    }
+
+    fn check_owner(&self, id: Uuid, src: SocketAddr) -> Result<(), Violation> {
+        match self.owners.get(&id) {
+            Some(&owner) if owner == src => Ok(()),
+            Some(_) => Err(Violation::NotOwner(id)),
+            None => Err(Violation::NoShip),
+        }
+    }
+
+    fn check_movement(&mut self, src: SocketAddr, pos: Point) -> Result<(), Violation> {
+        if !finite(pos) {
+            return Err(Violation::NotFinite)
+        }
+
+        let time = self.time;
+        let sender = self.senders.entry(src).or_default();
+
+        if let Some((last, at)) = sender.last_pos {
+            let distance = distance_2d(last, pos);
+            // updates can be bunched up by the network
+            let allowed = SHIP_SPEED * (time - at + MOVEMENT_SLACK) * MOVEMENT_TOLERANCE;
+            if distance > allowed {
+                return Err(Violation::TooFast { distance, allowed })
+            }
+        }
+        sender.last_pos = Some((pos, time));
+        Ok(())
+    }
+
+    // logs a violation, at most once per VIOLATION_LOG_INTERVAL per address
+    pub fn report(&mut self, src: SocketAddr, violation: &Violation) {
+        let sender = self.senders.entry(src).or_default();
+        if sender.log_timer > 0.0 {
+            sender.suppressed += 1;
+            return
+        }
+
+        if sender.suppressed > 0 {
+            eprintln!("rejected from {}: {} ({} more since the last report)", src, violation, sender.suppressed);
+        }
+        else {
+            eprintln!("rejected from {}: {}", src, violation);
+        }
+        sender.suppressed = 0;
+        sender.log_timer = VIOLATION_LOG_INTERVAL;
+    }
+}
+
+fn finite(pos: Point) -> bool {
+    pos.x.is_finite() && pos.y.is_finite()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entities::Bullet;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn boss_and_enemies_cant_be_claimed() {
+        let mut validator = Validator::new();
+        assert!(matches!(validator.claim(Uuid::nil(), addr(1)), Err(Violation::NotAPlayer(_))));
+        assert!(matches!(validator.claim(level::enemy_id(0, 0), addr(1)), Err(Violation::NotAPlayer(_))));
+
+        let boss = Ship::new(Possession::Enemy);
+        let claim = validator.check(&Wrapper::ShipWrapper(boss), addr(1), &[]);
+        assert!(matches!(claim, Err(Violation::NotAPlayer(_))));
+        assert!(validator.owned_by(addr(1)).is_empty());
+    }
+
+    #[test]
+    fn ships_spawn_with_at_most_full_health() {
+        let mut validator = Validator::new();
+        let mut ship = Ship::new(Possession::Player);
+        ship.health = 1e30;
+        let claim = validator.check(&Wrapper::ShipWrapper(ship), addr(1), &[]);
+        assert!(matches!(claim, Err(Violation::Health(_))));
+
+        ship.health = PLAYER_HEALTH;
+        assert!(validator.check(&Wrapper::ShipWrapper(ship), addr(1), &[]).is_ok());
+    }
+
+    #[test]
+    fn bullets_are_checked_against_the_senders_ship() {
+        let mut validator = Validator::new();
+        let ship = Ship::new(Possession::Player);
+        validator.claim(ship.id, addr(1)).unwrap();
+
+        let near = Bullet::new(Possession::Player, 0.0, ship.pos, BulletType::NORMAL, None);
+        assert!(validator.check(&Wrapper::BulletWrapper(near), addr(1), &[ship]).is_ok());
+
+        let far = Bullet::new(Possession::Player, 0.0, Point { x: 0.0, y: 0.0 }, BulletType::NORMAL, None);
+        let check = validator.check(&Wrapper::BulletWrapper(far), addr(1), &[ship]);
+        assert!(matches!(check, Err(Violation::BulletTooFar(_))));
+
+        // a second ship next to the bullet doesn't help
+        let mut other = Ship::new(Possession::Player);
+        other.pos = far.pos;
+        assert!(validator.claim(other.id, addr(1)).is_err());
+        let check = validator.check(&Wrapper::BulletWrapper(far), addr(1), &[ship, other]);
+        assert!(matches!(check, Err(Violation::BulletTooFar(_))));
+    }
+
+    #[test]
+    fn one_ship_per_address() {
+        let mut validator = Validator::new();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(matches!(validator.claim(first, addr(1)), Ok(true)));
+        assert!(matches!(validator.claim(first, addr(1)), Ok(false)));
+        assert!(matches!(validator.claim(second, addr(1)), Err(Violation::SecondShip(_))));
+        assert!(matches!(validator.claim(first, addr(2)), Err(Violation::NotOwner(_))));
+
+        validator.release(first);
+        assert!(matches!(validator.claim(second, addr(1)), Ok(true)));
+    }
+}